@@ -0,0 +1,211 @@
+use crate::fgpt::{CompletionSummary, Message};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// A request of the Anthropic Messages API.
+#[derive(Deserialize, Debug, Serialize, Default)]
+pub struct MessagesRequest {
+    pub model: Option<String>,
+    pub system: Option<Content>,
+    pub messages: Vec<AnthropicMessage>,
+    pub max_tokens: Option<usize>,
+    pub stop_sequences: Option<Vec<String>>,
+    pub stream: Option<bool>,
+}
+
+#[derive(Deserialize, Debug, Serialize)]
+pub struct AnthropicMessage {
+    pub role: String,
+    pub content: Content,
+}
+
+#[derive(Deserialize, Debug, Serialize)]
+#[serde(untagged)]
+pub enum Content {
+    Text(String),
+    Blocks(Vec<ContentBlock>),
+}
+
+#[derive(Deserialize, Debug, Serialize)]
+pub struct ContentBlock {
+    pub r#type: String,
+    pub text: Option<String>,
+}
+
+/// A request field the proxy can't translate.
+#[derive(Debug)]
+pub struct InvalidField {
+    pub param: String,
+    pub message: String,
+}
+
+impl Content {
+    /// The text of the content, only text blocks are supported.
+    fn text(&self, param: &str) -> Result<String, InvalidField> {
+        match self {
+            Content::Text(text) => Ok(text.clone()),
+            Content::Blocks(blocks) => blocks
+                .iter()
+                .enumerate()
+                .map(|(i, block)| match block.r#type.as_str() {
+                    "text" => Ok(block.text.clone().unwrap_or_default()),
+                    other => Err(InvalidField {
+                        param: format!("{}[{}].type", param, i),
+                        message: format!("content blocks of type {} are not supported", other),
+                    }),
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map(|texts| texts.join("\n")),
+        }
+    }
+}
+
+impl MessagesRequest {
+    /// The upstream messages, the system prompt first.
+    pub fn upstream_messages(&self) -> Result<Vec<Message>, InvalidField> {
+        let mut messages = vec![];
+        if let Some(system) = self.system.as_ref() {
+            messages.push(message("system", system.text("system")?));
+        }
+        for (index, m) in self.messages.iter().enumerate() {
+            if m.role != "user" && m.role != "assistant" {
+                return Err(InvalidField {
+                    param: format!("messages[{}].role", index),
+                    message: format!("unsupported role {}", m.role),
+                });
+            }
+            let content = m.content.text(&format!("messages[{}].content", index))?;
+            messages.push(message(&m.role, content));
+        }
+        Ok(messages)
+    }
+}
+
+fn message(role: &str, content: String) -> Message {
+    Message {
+        role: role.to_string(),
+        content,
+        content_type: None,
+    }
+}
+
+/// The id of a message, from the id of its upstream request.
+pub fn message_id(summary: &CompletionSummary) -> String {
+    let id = summary.request_id.trim_start_matches("chatcmpl-");
+    format!("msg_{}", id)
+}
+
+/// The `stop_reason` of a finished completion.
+pub fn stop_reason(summary: &CompletionSummary) -> &'static str {
+    match (summary.finish_reason.as_deref(), &summary.stop_sequence) {
+        (Some("length"), _) => "max_tokens",
+        (_, Some(_)) => "stop_sequence",
+        _ => "end_turn",
+    }
+}
+
+fn message_object(summary: &CompletionSummary, model: &str, content: Value) -> Value {
+    json!({
+        "id": message_id(summary),
+        "type": "message",
+        "role": "assistant",
+        "model": model,
+        "content": content,
+        "stop_reason": null,
+        "stop_sequence": null,
+        "usage": {
+            "input_tokens": summary.prompt_tokens,
+            "output_tokens": summary.completion_tokens,
+        }
+    })
+}
+
+/// The reply of a non-stream request.
+pub fn message_response(summary: &CompletionSummary, model: &str) -> Value {
+    let mut body = message_object(
+        summary,
+        model,
+        json!([{"type": "text", "text": summary.text}]),
+    );
+    body["stop_reason"] = json!(stop_reason(summary));
+    body["stop_sequence"] = json!(summary.stop_sequence);
+    body
+}
+
+/// The stream events of an OpenAI chunk delta: the role starts the message
+/// and its text block, the finish reason stops them.
+pub fn stream_events(
+    summary: &CompletionSummary,
+    model: &str,
+    delta: &Value,
+    finish_reason: Option<&str>,
+) -> Vec<(&'static str, Value)> {
+    let mut events = vec![];
+    if delta.get("role").is_some() {
+        let mut message = message_object(summary, model, json!([]));
+        message["usage"]["output_tokens"] = json!(0);
+        events.push((
+            "message_start",
+            json!({"type": "message_start", "message": message}),
+        ));
+        events.push((
+            "content_block_start",
+            json!({
+                "type": "content_block_start",
+                "index": 0,
+                "content_block": {"type": "text", "text": ""}
+            }),
+        ));
+        return events;
+    }
+    let text = delta["content"].as_str().unwrap_or_default();
+    if !text.is_empty() {
+        events.push((
+            "content_block_delta",
+            json!({
+                "type": "content_block_delta",
+                "index": 0,
+                "delta": {"type": "text_delta", "text": text}
+            }),
+        ));
+    }
+    if finish_reason.is_some() {
+        events.push((
+            "content_block_stop",
+            json!({"type": "content_block_stop", "index": 0}),
+        ));
+        events.push((
+            "message_delta",
+            json!({
+                "type": "message_delta",
+                "delta": {
+                    "stop_reason": stop_reason(summary),
+                    "stop_sequence": summary.stop_sequence,
+                },
+                "usage": {"output_tokens": summary.completion_tokens}
+            }),
+        ));
+    }
+    events
+}
+
+/// The Anthropic error type of a status code.
+pub fn error_type(status: u16) -> &'static str {
+    match status {
+        400 => "invalid_request_error",
+        401 => "authentication_error",
+        403 => "permission_error",
+        404 => "not_found_error",
+        413 => "request_too_large",
+        429 => "rate_limit_error",
+        529 => "overloaded_error",
+        _ => "api_error",
+    }
+}
+
+pub fn error_body(r#type: &str, message: &str) -> Value {
+    json!({
+        "type": "error",
+        "error": {"type": r#type, "message": message}
+    })
+}
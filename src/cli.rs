@@ -10,22 +10,54 @@ use std::io::{IsTerminal, Read};
 impl From<ReadlineError> for fgpt::Error {
     fn from(e: ReadlineError) -> Self {
         match e {
-            ReadlineError::Eof => fgpt::Error::Io("EOF".to_string()),
-            _ => fgpt::Error::Io(e.to_string()),
+            ReadlineError::Eof => fgpt::Error::Io(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "EOF",
+            )),
+            ReadlineError::Io(e) => fgpt::Error::Io(e),
+            _ => fgpt::Error::Io(std::io::Error::other(e)),
         }
     }
 }
 
+/// Log the error and give a hint for the failures the user can act on.
+fn report_error(state: &fgpt::AppStateRef, e: &fgpt::Error) {
+    log::error!("{}", e);
+    let e = match e {
+        fgpt::Error::Session(e) => {
+            println!("Alloc session fail, proxy: {:?}", state.proxy);
+            e.as_ref()
+        }
+        _ => e,
+    };
+    match e {
+        fgpt::Error::UnsupportedRegion(_) => {
+            println!("Your country is not supported yet, please consider using a U.S. VPN.");
+        }
+        fgpt::Error::RateLimited { retry_after, .. } => {
+            println!(
+                "Too many requests, please retry after {} secs.",
+                retry_after.unwrap_or(60)
+            );
+        }
+        fgpt::Error::Reqwest(_) | fgpt::Error::Timeout(_) => {
+            println!("If this error persists, your country may not be supported yet.");
+            println!("If your country was the issue, please consider using a U.S. VPN.");
+        }
+        _ => {}
+    }
+}
+
 #[derive(Default)]
 struct PromptHighlighter {}
 
 impl Highlighter for PromptHighlighter {
     fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
-        return Cow::Owned(format!("\x1b[33m{}\x1b[0m", line));
+        Cow::Owned(format!("\x1b[33m{}\x1b[0m", line))
     }
 
     fn highlight_char(&self, _line: &str, _pos: usize, _forced: bool) -> bool {
-        return true;
+        true
     }
 }
 
@@ -39,7 +71,7 @@ pub async fn run_repl(state: fgpt::AppStateRef) -> Result<(), fgpt::Error> {
     println!("free GPT-3.5 cli tools | 🪐 https://github.com/shenjinti/fgpt");
     println!("💖 To star the repository if you like \x1b[1;32mfgpt\x1b[0m!");
 
-    let help_texts = vec![
+    let help_texts = [
         "",
         "Type `\x1b[1;32m/help\x1b[0m` for more information.",
         "Type `\x1b[1;32m/exit\x1b[0m` or <\x1b[1;35mCtrl-D\x1b[0m> to exit the program.",
@@ -82,9 +114,9 @@ pub async fn run_repl(state: fgpt::AppStateRef) -> Result<(), fgpt::Error> {
                     _ => {}
                 }
 
-                if line.ends_with("\\") {
+                if let Some(line) = line.strip_suffix('\\') {
                     prompt_text = ".. ".to_string();
-                    question.push_str(&line[..line.len() - 1]);
+                    question.push_str(line);
                     question.push('\n');
                     continue;
                 } else {
@@ -95,15 +127,15 @@ pub async fn run_repl(state: fgpt::AppStateRef) -> Result<(), fgpt::Error> {
                 rl.add_history_entry(&question).ok();
                 question = String::new();
 
-                let mut messages = vec![];
-                messages.push(Message {
+                let messages = vec![Message {
                     role: "user".to_string(),
                     content: line.to_string(),
                     content_type: Some("text".to_string()),
-                });
+                }];
 
                 let req = CompletionRequest::new(
                     state.clone(),
+                    None,
                     messages,
                     conversation_id.clone(),
                     last_message_id.clone(),
@@ -112,29 +144,38 @@ pub async fn run_repl(state: fgpt::AppStateRef) -> Result<(), fgpt::Error> {
                 let mut stream = match req.stream(state.clone()).await {
                     Ok(stream) => stream,
                     Err(e) => {
-                        log::error!("{}", e);
+                        report_error(&state, &e);
                         continue;
                     }
                 };
 
-                while let Some(Ok(event)) = stream.next().await {
+                while let Some(event) = stream.next().await {
+                    let event = match event {
+                        Ok(event) => event,
+                        Err(fgpt::Error::Stream(e)) => {
+                            log::warn!("{}", e);
+                            continue;
+                        }
+                        Err(e) => {
+                            report_error(&state, &e);
+                            break;
+                        }
+                    };
                     match event {
                         CompletionEvent::Data(data) => {
                             data.delta_chars
                                 .map(|c| std::io::stdout().write(c.as_bytes()));
                         }
                         CompletionEvent::Error(reason) => {
-                            log::error!("{}", reason);
+                            report_error(&state, &fgpt::Error::from_completion(reason));
                             break;
                         }
                         CompletionEvent::Done => {
-                            conversation_id = stream.conversation_id.borrow().clone();
-                            last_message_id = stream.last_message_id.borrow().clone();
+                            let summary = stream.summary();
+                            conversation_id = summary.conversation_id.clone();
+                            last_message_id = summary.last_message_id.clone();
                             break;
                         }
-                        CompletionEvent::Text(text) => {
-                            print!("{}", text);
-                        }
                         _ => {}
                     }
                     std::io::stdout().flush().ok();
@@ -161,6 +202,21 @@ pub async fn run(state: fgpt::AppStateRef) -> Result<(), fgpt::Error> {
         return run_repl(state).await;
     }
 
+    let mut input = None;
+    if !std::io::stdin().is_terminal() {
+        // it may be a pipe or a file
+        let mut content = String::new();
+        std::io::stdin().read_to_string(&mut content)?;
+        input = Some(content);
+    }
+    run_with_input(state, input).await
+}
+
+/// Ask the question of `state`, with `input` as the content piped to stdin.
+pub async fn run_with_input(
+    state: fgpt::AppStateRef,
+    input: Option<String>,
+) -> Result<(), fgpt::Error> {
     let mut messages = vec![];
     if state.code {
         messages.push(Message {
@@ -187,10 +243,7 @@ pub async fn run(state: fgpt::AppStateRef) -> Result<(), fgpt::Error> {
         });
     }
 
-    if !std::io::stdin().is_terminal() {
-        // it may be a pipe or a file
-        let mut content = String::new();
-        std::io::stdin().read_to_string(&mut content)?;
+    if let Some(content) = input {
         messages.push(Message {
             role: "user".to_string(),
             content,
@@ -201,25 +254,40 @@ pub async fn run(state: fgpt::AppStateRef) -> Result<(), fgpt::Error> {
     messages.iter().for_each(|m| log::debug!("{:?}", m));
 
     let start_at = std::time::Instant::now();
-    let req = CompletionRequest::new(state.clone(), messages, None, None);
-    let mut stream = req.stream(state.clone()).await?;
+    let req = CompletionRequest::new(state.clone(), None, messages, None, None);
+    let mut stream = match req.stream(state.clone()).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            report_error(&state, &e);
+            return Err(e);
+        }
+    };
 
-    while let Some(Ok(event)) = stream.next().await {
+    while let Some(event) = stream.next().await {
+        let event = match event {
+            Ok(event) => event,
+            Err(fgpt::Error::Stream(e)) => {
+                log::warn!("{}", e);
+                continue;
+            }
+            Err(e) => {
+                report_error(&state, &e);
+                return Err(e);
+            }
+        };
         match event {
             CompletionEvent::Data(data) => {
                 data.delta_chars
                     .map(|c| std::io::stdout().write(c.as_bytes()));
             }
             CompletionEvent::Error(reason) => {
-                log::error!("{}", reason);
-                break;
+                let e = fgpt::Error::from_completion(reason);
+                report_error(&state, &e);
+                return Err(e);
             }
             CompletionEvent::Done => {
                 break;
             }
-            CompletionEvent::Text(text) => {
-                print!("{}", text);
-            }
             _ => {}
         }
         std::io::stdout().flush().ok();
@@ -227,14 +295,13 @@ pub async fn run(state: fgpt::AppStateRef) -> Result<(), fgpt::Error> {
     println!();
 
     let elapsed = start_at.elapsed().as_secs_f64();
-    let completion_tokens = *stream.completion_tokens.borrow();
-    let total_tokens = completion_tokens + stream.prompt_tokens;
-    let throughput = completion_tokens as f64 / elapsed as f64;
+    let summary = stream.into_summary();
+    let throughput = summary.completion_tokens as f64 / elapsed;
     let stats_text = format!(
         "Total tokens: \x1b[32m{}\x1b[0m, completion tokens: \x1b[32m{}\x1b[0m, prompt tokens: \x1b[32m{}\x1b[0m, elapsed: \x1b[33m{:.1}\x1b[0m secs, throughput: \x1b[33m{:.2}\x1b[0m tps",
-        total_tokens,
-        completion_tokens,
-        stream.prompt_tokens,
+        summary.total_tokens(),
+        summary.completion_tokens,
+        summary.prompt_tokens,
         elapsed,
         throughput
     );
@@ -0,0 +1,84 @@
+use crate::fgpt::Message;
+use serde::Serialize;
+use sha3::Digest;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Where an upstream conversation left off, so the next turn can continue it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConversationRef {
+    pub conversation_id: String,
+    pub last_message_id: String,
+    /// The `history_key` of the messages up to the last reply.
+    pub history: String,
+}
+
+/// Upstream conversations of the proxy clients, forgotten after `ttl`
+/// without a new turn.
+pub struct ConversationStore {
+    entries: Mutex<HashMap<String, (ConversationRef, Instant)>>,
+    ttl: Duration,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConversationStoreStats {
+    pub size: usize,
+    pub ttl_secs: u64,
+}
+
+impl ConversationStore {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<ConversationRef> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some((conversation, updated_at)) if updated_at.elapsed() < self.ttl => {
+                Some(conversation.clone())
+            }
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn put(&self, key: String, conversation: ConversationRef) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, (_, updated_at)| updated_at.elapsed() < self.ttl);
+        entries.insert(key, (conversation, Instant::now()));
+    }
+
+    pub fn stats(&self) -> ConversationStoreStats {
+        ConversationStoreStats {
+            size: self.entries.lock().unwrap().len(),
+            ttl_secs: self.ttl.as_secs(),
+        }
+    }
+}
+
+/// The key of a conversation named by the client.
+pub fn client_key(id: &str) -> String {
+    format!("client:{}", id)
+}
+
+/// The key of a conversation by its messages, the client sends them again
+/// with the next turn.
+pub fn history_key(messages: &[Message]) -> String {
+    let mut hasher = sha3::Sha3_256::new();
+    for message in messages {
+        hasher.update(message.role.as_bytes());
+        hasher.update([0]);
+        hasher.update(message.content.as_bytes());
+        hasher.update([0]);
+    }
+    format!("history:{}", hex::encode(hasher.finalize()))
+}
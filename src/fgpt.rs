@@ -1,37 +1,45 @@
+use crate::sse::{SseDecoder, SseFrame};
+use crate::tokens::{self, TokenCounter};
 use base64::{engine::general_purpose::STANDARD, Engine};
-use bytes::{Bytes, BytesMut};
+use bytes::Bytes;
 use chrono::{DateTime, Local};
 use futures::stream::Stream;
 use rand::seq::SliceRandom;
 use rand::Rng;
 use reqwest::{
     header::{
-        ACCEPT, ACCEPT_LANGUAGE, CACHE_CONTROL, CONTENT_TYPE, ORIGIN, PRAGMA, REFERER, USER_AGENT,
+        ACCEPT, ACCEPT_LANGUAGE, CACHE_CONTROL, CONTENT_TYPE, ORIGIN, PRAGMA, REFERER, RETRY_AFTER,
+        USER_AGENT,
     },
     Client, Proxy,
 };
 use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
 use sha3::Digest;
-use std::cell::RefCell;
-use std::time::SystemTime;
+use std::future::Future;
+use std::time::{Duration, SystemTime};
 use std::{
     collections::HashMap,
     fmt,
     pin::Pin,
-    sync::Arc,
+    sync::{Arc, OnceLock},
     task::{Context, Poll},
 };
 
-const OPENAI_ENDPOINT: &str = "https://chat.openai.com";
-const OPENAI_API_URL: &str = "https://chat.openai.com/backend-anon/conversation";
-const OPENAI_SENTINEL_URL: &str = "https://chat.openai.com/backend-anon/sentinel/chat-requirements";
+pub const OPENAI_ENDPOINT: &str = "https://chat.openai.com";
+const OPENAI_API_PATH: &str = "/backend-anon/conversation";
+const OPENAI_SENTINEL_PATH: &str = "/backend-anon/sentinel/chat-requirements";
 const UA: &str = "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/121.0.0.0 Safari/537.36 Edg/121.0.0.0";
 const CH_UA: &str = r#""Not A(Brand";v="99", "Microsoft Edge";v="121", "Chromium";v="121""#;
 const CH_PLATFORM: &str = r#""macOS""#;
+const PROOF_MAX_ATTEMPTS: usize = 1_000_000;
 
 #[derive(Clone)]
 pub struct AppState {
     pub proxy: Option<String>,
+    /// Base URL of the upstream, without the trailing slash.
+    pub upstream: String,
+    /// Shared by every upstream request, so connections are pooled.
+    pub client: Client,
     pub device_id: String,
     pub code: bool,
     pub model: String,
@@ -40,37 +48,261 @@ pub struct AppState {
     pub input_file: Option<String>,
     pub repl: bool,
     pub dump_stats: bool,
+    pub retry: RetryPolicy,
 
     #[cfg(feature = "proxy")]
     pub prefix: String,
     #[cfg(feature = "proxy")]
     pub serve_addr: String,
+    /// Idle time before a streamed response gets a keep-alive comment.
+    #[cfg(feature = "proxy")]
+    pub sse_keep_alive: Option<Duration>,
+    #[cfg(feature = "proxy")]
+    pub metrics: Arc<crate::proxy::ProxyMetrics>,
+    /// Ready sessions, only pre-warmed when serving the proxy.
+    #[cfg(feature = "proxy")]
+    pub session_pool: Option<Arc<crate::pool::SessionPool>>,
+    /// Upper bound of the `n` choices of a proxied request.
+    #[cfg(feature = "proxy")]
+    pub max_choices: usize,
+    /// How many times a reply that doesn't match the `response_format` is
+    /// asked again.
+    #[cfg(feature = "proxy")]
+    pub json_retries: usize,
+    /// Serve the Anthropic Messages API next to the OpenAI one.
+    #[cfg(feature = "proxy")]
+    pub anthropic: bool,
+    /// Upstream conversations of the clients, when continuity is enabled.
+    #[cfg(feature = "proxy")]
+    pub conversations: Option<Arc<crate::conversations::ConversationStore>>,
+    /// Public model names mapped to the upstream model slugs.
+    #[cfg(feature = "proxy")]
+    pub model_aliases: Vec<(String, String)>,
 }
 
 pub type AppStateRef = Arc<AppState>;
 
+/// Settings of the HTTP client used for the upstream.
+#[derive(Clone, Debug)]
+pub struct ClientOptions {
+    pub connect_timeout: Duration,
+    pub read_timeout: Option<Duration>,
+    pub timeout: Option<Duration>,
+    pub tcp_keepalive: Option<Duration>,
+    pub pool_idle_timeout: Option<Duration>,
+    pub http2: bool,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(10),
+            read_timeout: Some(Duration::from_secs(60)),
+            timeout: None,
+            tcp_keepalive: Some(Duration::from_secs(30)),
+            pool_idle_timeout: Some(Duration::from_secs(90)),
+            http2: true,
+        }
+    }
+}
+
+impl ClientOptions {
+    pub fn build(&self, proxy: Option<&str>) -> Client {
+        let mut builder = Client::builder()
+            .connect_timeout(self.connect_timeout)
+            .tcp_keepalive(self.tcp_keepalive)
+            .pool_idle_timeout(self.pool_idle_timeout);
+        if let Some(read_timeout) = self.read_timeout {
+            builder = builder.read_timeout(read_timeout);
+        }
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if self.http2 {
+            builder = builder
+                .http2_adaptive_window(true)
+                .http2_keep_alive_interval(self.tcp_keepalive)
+                .http2_keep_alive_while_idle(true);
+        } else {
+            builder = builder.http1_only();
+        }
+        if let Some(proxy) = proxy {
+            match Proxy::all(proxy) {
+                Ok(proxy) => builder = builder.proxy(proxy),
+                Err(e) => log::warn!("setup proxy error: {:?}, ignore proxy: {}", e, proxy),
+            }
+        }
+        builder.build().unwrap_or_else(|e| {
+            log::warn!("build client error: {:?}, use default client", e);
+            Client::new()
+        })
+    }
+}
+
+/// How to retry the upstream calls made before the first byte is streamed.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub retry_statuses: Vec<u16>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(8),
+            retry_statuses: vec![429, 500, 502, 503, 504],
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn is_retryable(&self, e: &Error) -> bool {
+        match e {
+            Error::Session(e) => self.is_retryable(e),
+            Error::Upstream { status, .. } => self.retry_statuses.contains(status),
+            Error::RateLimited { .. } => self.retry_statuses.contains(&429),
+            Error::Reqwest(_) | Error::Timeout(_) | Error::ProofOfWork { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Exponential backoff with jitter, `attempt` starts at 1.
+    pub fn backoff(&self, attempt: u32, e: &Error) -> Duration {
+        let retry_after = match e {
+            Error::RateLimited {
+                retry_after: Some(secs),
+                ..
+            } => Some(Duration::from_secs(*secs)),
+            Error::Session(e) => match e.as_ref() {
+                Error::RateLimited {
+                    retry_after: Some(secs),
+                    ..
+                } => Some(Duration::from_secs(*secs)),
+                _ => None,
+            },
+            _ => None,
+        };
+        if let Some(retry_after) = retry_after {
+            return retry_after.min(self.max_delay);
+        }
+        let delay = self
+            .base_delay
+            .saturating_mul(2_u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay);
+        let half = delay.as_millis() as u64 / 2;
+        Duration::from_millis(half + rand::thread_rng().gen_range(0..=half))
+    }
+
+    pub async fn retry<T, F, Fut>(&self, what: &str, mut f: F) -> Result<T, Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match f().await {
+                Ok(v) => return Ok(v),
+                Err(e) if attempt < self.max_attempts && self.is_retryable(&e) => {
+                    let delay = self.backoff(attempt, &e);
+                    log::warn!(
+                        "{} attempt {}/{} failed: {}, retry in {} ms",
+                        what,
+                        attempt,
+                        self.max_attempts,
+                        e,
+                        delay.as_millis()
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
-    Io(String),
-    Reqwest(String),
-    Serde(String),
+    Io(std::io::Error),
+    Reqwest(reqwest::Error),
+    Serde(serde_json::Error),
+    /// The upstream request did not finish in time.
+    Timeout(reqwest::Error),
+    /// The upstream answered with a non-success status.
+    Upstream {
+        status: u16,
+        body: String,
+    },
+    /// The upstream throttles us, `retry_after` is in seconds.
+    RateLimited {
+        retry_after: Option<u64>,
+        message: String,
+    },
+    /// The upstream refuses to serve the country we connect from.
+    UnsupportedRegion(String),
+    /// The sentinel could not allocate a session.
+    Session(Box<Error>),
+    /// No proof of work was found for the sentinel challenge.
+    ProofOfWork {
+        seed: String,
+        difficulty: String,
+    },
+    /// The upstream reported an error inside the event stream.
+    Completion(String),
+    /// The event stream could not be decoded.
+    Stream(String),
+}
+
+impl Error {
+    /// Classify a non-success upstream response.
+    pub fn from_upstream(status: u16, retry_after: Option<u64>, body: String) -> Self {
+        if status == 429 {
+            return Error::RateLimited {
+                retry_after,
+                message: body,
+            };
+        }
+        if status == 403 && body.contains("unsupported_country") {
+            return Error::UnsupportedRegion(body);
+        }
+        Error::Upstream { status, body }
+    }
+
+    /// Classify the error reason sent by the upstream inside the event stream.
+    pub fn from_completion(reason: String) -> Self {
+        let lower = reason.to_lowercase();
+        if lower.contains("too many requests") || lower.contains("rate limit") {
+            return Error::RateLimited {
+                retry_after: None,
+                message: reason,
+            };
+        }
+        Error::Completion(reason)
+    }
 }
 
 impl From<std::io::Error> for Error {
     fn from(e: std::io::Error) -> Self {
-        Error::Io(e.to_string())
+        Error::Io(e)
     }
 }
 
 impl From<reqwest::Error> for Error {
     fn from(e: reqwest::Error) -> Self {
-        Error::Reqwest(e.to_string())
+        if e.is_timeout() {
+            return Error::Timeout(e);
+        }
+        Error::Reqwest(e)
     }
 }
 
 impl From<serde_json::Error> for Error {
     fn from(e: serde_json::Error) -> Self {
-        Error::Serde(e.to_string())
+        Error::Serde(e)
     }
 }
 
@@ -80,6 +312,30 @@ impl fmt::Display for Error {
             Error::Io(e) => write!(f, "IO error: {}", e),
             Error::Reqwest(e) => write!(f, "Reqwest error: {}", e),
             Error::Serde(e) => write!(f, "Serde error: {}", e),
+            Error::Timeout(e) => write!(f, "Timeout: {}", e),
+            Error::Upstream { status, body } => write!(f, "Upstream error {}: {}", status, body),
+            Error::RateLimited { message, .. } => write!(f, "Rate limited: {}", message),
+            Error::UnsupportedRegion(_) => write!(f, "Unsupported region"),
+            Error::Session(e) => write!(f, "Alloc session error: {}", e),
+            Error::ProofOfWork { seed, difficulty } => write!(
+                f,
+                "Proof of work not found, seed: {} difficulty: {}",
+                seed, difficulty
+            ),
+            Error::Completion(reason) => write!(f, "Completion error: {}", reason),
+            Error::Stream(e) => write!(f, "Stream error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Reqwest(e) | Error::Timeout(e) => Some(e),
+            Error::Serde(e) => Some(e),
+            Error::Session(e) => Some(e.as_ref()),
+            _ => None,
         }
     }
 }
@@ -88,8 +344,7 @@ impl fmt::Display for Error {
 pub struct Session {
     pub start_at: SystemTime,
     pub token: String,
-    pub proof_seed: String,
-    pub proof_difficulty: String,
+    pub proof_token: String,
     pub device_id: String,
 }
 
@@ -106,7 +361,7 @@ struct ChatRequirementsResponse {
     pub proofofwork: ChatRequirementsProofofwork,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 pub struct Message {
     pub role: String,
     pub content: String,
@@ -152,8 +407,10 @@ pub struct CompletionRequest {
 }
 
 impl CompletionRequest {
+    /// A request of `model`, the one of `state` when `None`.
     pub fn new(
         state: AppStateRef,
+        model: Option<String>,
         messages: Vec<Message>,
         conversation_id: Option<String>,
         parent_message_id: Option<String>,
@@ -164,7 +421,7 @@ impl CompletionRequest {
         Self {
             action: "next".to_string(),
             messages,
-            model: state.model.clone(),
+            model: model.unwrap_or_else(|| state.model.clone()),
             conversation_mode: {
                 let mut map = HashMap::new();
                 map.insert("kind".to_string(), "primary_assistant".to_string());
@@ -179,15 +436,30 @@ impl CompletionRequest {
         }
     }
 
+    /// Open the completion stream, retrying with `state.retry` until the
+    /// upstream accepts the conversation.
     pub async fn stream(&self, state: AppStateRef) -> Result<CompletionStream, Error> {
+        state
+            .retry
+            .retry("open stream", || self.open_stream(state.clone()))
+            .await
+    }
+
+    async fn open_stream(&self, state: AppStateRef) -> Result<CompletionStream, Error> {
         let start_at = std::time::Instant::now();
-        let session = alloc_session(state.clone()).await?;
+        #[cfg(feature = "proxy")]
+        let pooled = state.session_pool.as_ref().and_then(|pool| pool.take());
+        #[cfg(not(feature = "proxy"))]
+        let pooled = None;
+        let session = match pooled {
+            Some(session) => session,
+            None => alloc_session(state.clone()).await?,
+        };
         let builder = build_req(
-            OPENAI_API_URL,
+            OPENAI_API_PATH,
             &session.device_id,
             Some(&session.token),
-            Some(&session.proof_seed),
-            Some(&session.proof_difficulty),
+            Some(&session.proof_token),
             state.clone(),
         )?;
         let body = serde_json::to_string(&self)?;
@@ -203,8 +475,7 @@ impl CompletionRequest {
         self.messages.iter().for_each(|m| log::debug!("{:?}", m));
 
         if !resp.status().is_success() {
-            let resp_body = resp.text().await?;
-            return Err(Error::Reqwest(resp_body));
+            return Err(upstream_error(resp).await);
         }
 
         let tokenizer = gpt_tokenizer::Default::new();
@@ -222,53 +493,36 @@ impl CompletionRequest {
 
         Ok(CompletionStream {
             response_stream: Box::pin(resp.bytes_stream()),
-            buffer: BytesMut::new(),
+            decoder: SseDecoder::new(),
+            eof: false,
+            held: None,
             tokenizer,
-            prompt_tokens,
-            completion_tokens: RefCell::new(0),
-            textbuf: RefCell::new(String::new()),
-            conversation_id: RefCell::new(None),
-            last_message_id: RefCell::new(None),
-            finish_reason: RefCell::new(None),
-            request_id: format!("chatcmpl-{}", completion_id),
-            start_at: SystemTime::now(),
+            token_counter: TokenCounter::new(),
+            limits: CompletionLimits::default(),
+            summary: CompletionSummary {
+                request_id: format!("chatcmpl-{}", completion_id),
+                start_at: SystemTime::now(),
+                prompt_tokens,
+                completion_tokens: 0,
+                text: String::new(),
+                conversation_id: None,
+                last_message_id: None,
+                finish_reason: None,
+                stop_sequence: None,
+                model_slug: None,
+            },
         })
     }
 }
 
 #[derive(Debug)]
 pub enum CompletionEvent {
-    Data(CompletionResponse),
+    Data(Box<CompletionResponse>),
     Done,
     Heartbeat,
-    #[allow(unused)]
-    Text(String),
     Error(String),
 }
 
-impl From<&BytesMut> for CompletionEvent {
-    fn from(line: &BytesMut) -> CompletionEvent {
-        let line_str = String::from_utf8_lossy(&line).to_string();
-        let line_str = line_str.strip_prefix("data: ").unwrap_or(&line_str);
-        log::debug!(">> {:?}", line_str);
-        if line_str == "[DONE]" {
-            return CompletionEvent::Done;
-        }
-        let heartbeat_re =
-            regex::Regex::new(r"^\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}.\d{6}$").unwrap();
-        if heartbeat_re.is_match(line_str) {
-            CompletionEvent::Heartbeat
-        } else {
-            match serde_json::from_str(line_str) {
-                Ok(data) => CompletionEvent::Data(data),
-                Err(e) => {
-                    log::error!("parse error: {:?}", e);
-                    CompletionEvent::Text(line_str.to_string())
-                }
-            }
-        }
-    }
-}
 #[allow(unused)]
 #[derive(Debug, Deserialize)]
 pub struct CompletionMessageAuthor {
@@ -345,96 +599,246 @@ impl CompletionResponse {
     }
 }
 
-pub struct CompletionStream {
-    response_stream: Pin<Box<dyn Stream<Item = Result<Bytes, reqwest::Error>> + Send>>,
-    buffer: BytesMut,
-    tokenizer: gpt_tokenizer::Default,
-    pub prompt_tokens: i32,
-    pub completion_tokens: RefCell<i32>,
-    pub textbuf: RefCell<String>,
-    pub conversation_id: RefCell<Option<String>>,
-    pub last_message_id: RefCell<Option<String>>,
-    pub finish_reason: RefCell<Option<String>>,
+/// What a completion has produced so far, a snapshot of the stream state.
+#[derive(Debug, Clone)]
+pub struct CompletionSummary {
     pub request_id: String,
     pub start_at: SystemTime,
+    pub prompt_tokens: i32,
+    pub completion_tokens: i32,
+    pub text: String,
+    pub conversation_id: Option<String>,
+    pub last_message_id: Option<String>,
+    pub finish_reason: Option<String>,
+    /// The stop sequence the text was cut at.
+    pub stop_sequence: Option<String>,
+    pub model_slug: Option<String>,
 }
 
-impl CompletionStream {
+impl CompletionSummary {
     pub fn total_tokens(&self) -> i32 {
-        self.prompt_tokens + *self.completion_tokens.borrow()
+        self.prompt_tokens + self.completion_tokens
     }
 
-    fn get_next_event(self: &mut Self) -> Option<CompletionEvent> {
-        if let Some(pos) = self.buffer.windows(2).position(|window| window == b"\n\n") {
-            let mut line = self.buffer.split_to(pos + 2);
-            line.truncate(pos);
+    pub fn elapsed(&self) -> Duration {
+        self.start_at.elapsed().unwrap_or_default()
+    }
+}
 
-            let line_str = String::from_utf8_lossy(&line).to_string();
-            let line_str = line_str.strip_prefix("data: ").unwrap_or(&line_str);
-            if line_str == "[DONE]" {
-                return Some(CompletionEvent::Done);
-            }
-            let heartbeat_re =
-                regex::Regex::new(r"^\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}.\d{6}$").unwrap();
-            if heartbeat_re.is_match(line_str) {
-                return Some(CompletionEvent::Heartbeat);
+/// Limits enforced on the text as it arrives, the upstream has no such
+/// options.
+#[derive(Debug, Clone, Default)]
+pub struct CompletionLimits {
+    pub max_tokens: Option<usize>,
+    pub stop: Vec<String>,
+}
+
+impl CompletionLimits {
+    /// Where the first stop sequence starts, searching after `from`, and
+    /// which one it is.
+    fn find_stop(&self, text: &str, from: usize) -> Option<(usize, &str)> {
+        self.stop
+            .iter()
+            .filter_map(|stop| Some((text[from..].find(stop.as_str())?, stop.as_str())))
+            .min_by_key(|(pos, _)| *pos)
+            .map(|(pos, stop)| (from + pos, stop))
+    }
+
+    /// The end of the text that can't be the start of a stop sequence.
+    fn safe_end(&self, text: &str) -> usize {
+        let held = self
+            .stop
+            .iter()
+            .filter_map(|stop| {
+                (1..stop.len())
+                    .rev()
+                    .filter(|&len| stop.is_char_boundary(len))
+                    .find(|&len| text.ends_with(&stop[..len]))
+            })
+            .max()
+            .unwrap_or(0);
+        text.len() - held
+    }
+}
+
+pub struct CompletionStream {
+    response_stream: Pin<Box<dyn Stream<Item = Result<Bytes, reqwest::Error>> + Send + Sync>>,
+    decoder: SseDecoder,
+    eof: bool,
+    tokenizer: gpt_tokenizer::Default,
+    token_counter: TokenCounter,
+    limits: CompletionLimits,
+    /// The last frame, when the end of its text is held back as the start
+    /// of a stop sequence.
+    held: Option<SseFrame>,
+    summary: CompletionSummary,
+}
+
+impl CompletionStream {
+    pub fn summary(&self) -> &CompletionSummary {
+        &self.summary
+    }
+
+    pub fn into_summary(self) -> CompletionSummary {
+        self.summary
+    }
+
+    pub fn set_limits(&mut self, limits: CompletionLimits) {
+        self.limits = limits;
+    }
+
+    /// Drop the upstream response once a limit is hit, only `[DONE]` is left.
+    fn close(&mut self) {
+        self.response_stream = Box::pin(futures::stream::empty());
+        self.decoder = SseDecoder::new();
+        self.decoder.push(b"data: [DONE]\n\n");
+        self.decoder.finish();
+        self.eof = true;
+    }
+
+    /// Send the text held back by the last frame, the reply ended without
+    /// completing a stop sequence.
+    fn release_held(&mut self) -> Option<Result<CompletionEvent, Error>> {
+        let frame = self.held.take()?;
+        self.handle_frame(frame, true)
+    }
+
+    /// Turn a frame into an event, `None` for the frames we skip. With
+    /// `flush`, no text is held back.
+    fn handle_frame(
+        &mut self,
+        frame: SseFrame,
+        flush: bool,
+    ) -> Option<Result<CompletionEvent, Error>> {
+        log::debug!(">> {:?}", frame);
+        if frame.data == "[DONE]" {
+            if let Some(event) = self.release_held() {
+                // `[DONE]` follows the released text
+                self.close();
+                return Some(event);
             }
+            return Some(Ok(CompletionEvent::Done));
+        }
+        if heartbeat_re().is_match(&frame.data) {
+            return Some(Ok(CompletionEvent::Heartbeat));
+        }
 
-            let mut resp = serde_json::from_str::<CompletionResponse>(line_str).ok()?;
-            match resp.message.as_ref() {
-                Some(message) => {
-                    if message.author.role != "assistant" {
-                        return None;
-                    }
-                    let text = message.content.parts.join("\n");
-                    if self.textbuf.borrow().len() > text.len() {
-                        return None;
-                    }
-                    resp.delta_chars = Some(text[self.textbuf.borrow().len()..].to_string());
-                    *self.conversation_id.borrow_mut() = Some(resp.conversation_id.clone());
-                    *self.last_message_id.borrow_mut() = Some(message.id.clone());
-                    *self.finish_reason.borrow_mut() = resp.get_finish_reason();
-                    *self.textbuf.borrow_mut() = text.clone();
-
-                    *self.completion_tokens.borrow_mut() =
-                        self.tokenizer.encode(&text).len() as i32;
-                    return Some(CompletionEvent::Data(resp));
+        let value = match serde_json::from_str::<serde_json::Value>(&frame.data) {
+            Ok(value) => value,
+            Err(e) => {
+                return Some(Err(Error::Stream(format!(
+                    "malformed frame: {}: {:?}",
+                    e, frame.data
+                ))))
+            }
+        };
+        // other kinds of events, such as moderation or title updates
+        let mut resp = match serde_json::from_value::<CompletionResponse>(value) {
+            Ok(resp) => resp,
+            Err(e) => {
+                log::debug!("skip frame: {}", e);
+                return None;
+            }
+        };
+        match resp.message.as_ref() {
+            Some(message) => {
+                if message.author.role != "assistant" {
+                    return None;
+                }
+                let mut text = message.content.parts.join("\n");
+                let sent = self.summary.text.len();
+                if sent > text.len() {
+                    return None;
+                }
+                let mut finish_reason = resp.get_finish_reason();
+                let mut limited = false;
+                let mut held = false;
+                if let Some((end, stop)) = self.limits.find_stop(&text, sent) {
+                    self.summary.stop_sequence = Some(stop.to_string());
+                    text.truncate(end);
+                    finish_reason = Some("stop".to_string());
+                    limited = true;
+                } else if finish_reason.is_none() && !flush {
+                    // hold back what may be the start of a stop sequence
+                    let end = self.limits.safe_end(&text).max(sent);
+                    held = end < text.len();
+                    text.truncate(end);
                 }
-                _ => match resp.error.as_ref() {
-                    Some(error) => {
-                        return Some(CompletionEvent::Error(error.clone()));
+                let mut completion_tokens = self.token_counter.update(&self.tokenizer, &text);
+                // a trailing space may still merge with the next word, so the
+                // text is only cut once it goes over the limit
+                if let Some(max_tokens) = self.limits.max_tokens {
+                    if completion_tokens > max_tokens {
+                        let end = tokens::truncate(&self.tokenizer, &text, max_tokens);
+                        text.truncate(end.max(sent));
+                        completion_tokens = max_tokens;
+                        finish_reason = Some("length".to_string());
+                        limited = true;
                     }
-                    None => {}
-                },
+                }
+
+                let summary = &mut self.summary;
+                resp.delta_chars = Some(text[sent..].to_string());
+                summary.conversation_id = Some(resp.conversation_id.clone());
+                summary.last_message_id = Some(message.id.clone());
+                summary.finish_reason = finish_reason;
+                if message.metadata.model_slug.is_some() {
+                    summary.model_slug = message.metadata.model_slug.clone();
+                }
+                summary.completion_tokens = completion_tokens as i32;
+                summary.text = text;
+                self.held = held.then_some(frame);
+                if limited {
+                    self.close();
+                }
+                Some(Ok(CompletionEvent::Data(Box::new(resp))))
+            }
+            None => resp
+                .error
+                .as_ref()
+                .map(|error| Ok(CompletionEvent::Error(error.clone()))),
+        }
+    }
+
+    /// Drain the frames already buffered before reading from the network.
+    fn get_next_event(&mut self) -> Option<Result<CompletionEvent, Error>> {
+        while let Some(frame) = self.decoder.next_frame() {
+            let event = match frame {
+                Ok(frame) => self.handle_frame(frame, false),
+                Err(e) => Some(Err(e)),
+            };
+            if event.is_some() {
+                return event;
             }
         }
         None
     }
 }
 
+fn heartbeat_re() -> &'static regex::Regex {
+    static HEARTBEAT_RE: OnceLock<regex::Regex> = OnceLock::new();
+    HEARTBEAT_RE
+        .get_or_init(|| regex::Regex::new(r"^\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}.\d{6}$").unwrap())
+}
+
 impl Stream for CompletionStream {
-    type Item = reqwest::Result<CompletionEvent>;
+    type Item = Result<CompletionEvent, Error>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         loop {
+            if let Some(event) = self.get_next_event() {
+                return Poll::Ready(Some(event));
+            }
+            if self.eof {
+                // the upstream closed without `[DONE]`
+                return Poll::Ready(self.release_held());
+            }
             match self.response_stream.as_mut().poll_next(cx) {
-                Poll::Ready(Some(Ok(data))) => {
-                    self.buffer.extend_from_slice(&data);
-                    match self.get_next_event() {
-                        Some(event) => return Poll::Ready(Some(Ok(event))),
-                        None => continue,
-                    }
-                }
-                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(Some(Ok(data))) => self.decoder.push(&data),
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e.into()))),
                 Poll::Ready(None) => {
-                    if !self.buffer.is_empty() {
-                        match self.get_next_event() {
-                            Some(event) => return Poll::Ready(Some(Ok(event))),
-                            None => continue,
-                        }
-                    } else {
-                        return Poll::Ready(None);
-                    }
+                    self.eof = true;
+                    self.decoder.finish();
                 }
                 Poll::Pending => return Poll::Pending,
             }
@@ -443,27 +847,19 @@ impl Stream for CompletionStream {
 }
 
 fn build_req(
-    url: &str,
+    path: &str,
     device_id: &str,
     token: Option<&str>,
-    seed: Option<&str>,
-    difficulty: Option<&str>,
+    proof_token: Option<&str>,
     state: AppStateRef,
-) -> Result<reqwest::RequestBuilder, reqwest::Error> {
-    let client = match state.proxy.as_ref() {
-        Some(proxy) => match Proxy::all(proxy) {
-            Ok(proxy) => Client::builder().proxy(proxy).build().ok(),
-            Err(e) => {
-                log::warn!("setup proxy error: {:?}, ignore proxy: {}", e, proxy);
-                None
-            }
-        },
-        None => None,
-    }
-    .unwrap_or(Client::new());
-
+) -> Result<reqwest::RequestBuilder, Error> {
+    let url = format!("{}{}", state.upstream, path);
+    let origin = reqwest::Url::parse(&state.upstream)
+        .map(|url| url.origin().ascii_serialization())
+        .unwrap_or_else(|_| state.upstream.clone());
     let short_lang = state.lang.split('-').next().unwrap_or("en");
-    let mut builder = client
+    let mut builder = state
+        .client
         .post(url)
         .header("oai-language", state.lang.clone())
         .header("oai-device-id", device_id)
@@ -474,8 +870,8 @@ fn build_req(
         )
         .header(CACHE_CONTROL, "no-cache")
         .header(PRAGMA, "no-cache")
-        .header(REFERER, OPENAI_ENDPOINT)
-        .header(ORIGIN, OPENAI_ENDPOINT)
+        .header(REFERER, &state.upstream)
+        .header(ORIGIN, origin)
         .header(CONTENT_TYPE, "application/json")
         .header("sec-ch-ua", CH_UA)
         .header("sec-ch-ua-mobile", "?0")
@@ -485,8 +881,7 @@ fn build_req(
         .header("sec-fetch-site", "same-origin")
         .header(USER_AGENT, UA);
 
-    if let Some(seed) = seed {
-        let proof_token = openai_sentinel_proof_token(seed, difficulty.unwrap());
+    if let Some(proof_token) = proof_token {
         builder = builder.header("openai-sentinel-proof-token", proof_token);
     }
 
@@ -497,30 +892,41 @@ fn build_req(
     }
 }
 
+/// Turn a non-success upstream response into a typed error.
+async fn upstream_error(resp: reqwest::Response) -> Error {
+    let status = resp.status().as_u16();
+    let retry_after = resp
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    match resp.text().await {
+        Ok(body) => Error::from_upstream(status, retry_after, body),
+        Err(e) => e.into(),
+    }
+}
+
 pub async fn alloc_session(state: AppStateRef) -> Result<Session, Error> {
     let start_at = SystemTime::now();
     let resp = build_req(
-        OPENAI_SENTINEL_URL,
+        OPENAI_SENTINEL_PATH,
         &state.device_id,
         None,
         None,
-        None,
         state.clone(),
     )?
     .send()
-    .await;
-
-    let resp = match resp {
-        Ok(resp) => resp,
-        Err(e) => {
-            println!("Alloc session fail, proxy: {:?}", state.proxy);
-            println!("If this error persists, your country may not be supported yet.");
-            println!("If your country was the issue, please consider using a U.S. VPN.");
-            return Err(e.into());
-        }
-    };
+    .await
+    .map_err(|e| Error::Session(Box::new(e.into())))?;
 
-    let data = resp.json::<ChatRequirementsResponse>().await?;
+    if !resp.status().is_success() {
+        return Err(Error::Session(Box::new(upstream_error(resp).await)));
+    }
+
+    let data = resp
+        .json::<ChatRequirementsResponse>()
+        .await
+        .map_err(|e| Error::Session(Box::new(e.into())))?;
 
     log::debug!(
         "alloc session: {} ms, proxy: {:?} -> {:?}",
@@ -529,16 +935,23 @@ pub async fn alloc_session(state: AppStateRef) -> Result<Session, Error> {
         data,
     );
 
+    let ChatRequirementsProofofwork {
+        seed, difficulty, ..
+    } = data.proofofwork;
+    let proof_token =
+        tokio::task::spawn_blocking(move || openai_sentinel_proof_token(&seed, &difficulty))
+            .await
+            .map_err(|e| Error::Io(std::io::Error::other(e)))??;
+
     Ok(Session {
         start_at,
         token: data.token,
-        proof_seed: data.proofofwork.seed,
-        proof_difficulty: data.proofofwork.difficulty,
+        proof_token,
         device_id: state.device_id.clone(),
     })
 }
 
-fn openai_sentinel_proof_token(seed: &str, difficulty: &str) -> String {
+fn openai_sentinel_proof_token(seed: &str, difficulty: &str) -> Result<String, Error> {
     let datetime = Local::now()
         .format("%a %b %-d %Y %T GMT%z (%Z)")
         .to_string();
@@ -547,7 +960,7 @@ fn openai_sentinel_proof_token(seed: &str, difficulty: &str) -> String {
     let mut hasher = sha3::Sha3_512::new();
     let mut rng = rand::thread_rng();
 
-    loop {
+    for _ in 0..PROOF_MAX_ATTEMPTS {
         let first_key = [8, 12, 16, 24].choose(&mut rng).unwrap()
             + [3000, 4000, 6000].choose(&mut rng).unwrap();
 
@@ -558,7 +971,11 @@ fn openai_sentinel_proof_token(seed: &str, difficulty: &str) -> String {
         hasher.update(format!("{}{}", seed, value));
         let hash = hasher.finalize_reset();
         if hex::encode(&hash[..diff_len]) <= difficulty {
-            return format!("gAAAAAB{}", value);
+            return Ok(format!("gAAAAAB{}", value));
         }
     }
+    Err(Error::ProofOfWork {
+        seed: seed.to_string(),
+        difficulty,
+    })
 }
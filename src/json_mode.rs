@@ -0,0 +1,161 @@
+use crate::tools;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// The `response_format` of a chat request.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Text,
+    JsonObject,
+    JsonSchema { json_schema: JsonSchema },
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct JsonSchema {
+    pub name: Option<String>,
+    #[serde(default)]
+    pub schema: Option<Value>,
+}
+
+impl ResponseFormat {
+    /// The schema the reply must match, `None` for free text.
+    fn schema(&self) -> Option<Value> {
+        match self {
+            ResponseFormat::Text => None,
+            ResponseFormat::JsonObject => Some(serde_json::json!({"type": "object"})),
+            ResponseFormat::JsonSchema { json_schema } => Some(
+                json_schema
+                    .schema
+                    .clone()
+                    .unwrap_or_else(|| serde_json::json!({"type": "object"})),
+            ),
+        }
+    }
+
+    pub fn is_json(&self) -> bool {
+        !matches!(self, ResponseFormat::Text)
+    }
+
+    /// The instruction asking the model for JSON only.
+    pub fn system_prompt(&self) -> Option<String> {
+        let prompt = "Reply with only a valid JSON object, without code fences or any other text.";
+        match self {
+            ResponseFormat::Text => None,
+            ResponseFormat::JsonObject => Some(prompt.to_string()),
+            ResponseFormat::JsonSchema { .. } => Some(format!(
+                "{} The object must match this JSON schema:\n{}",
+                prompt,
+                serde_json::to_string_pretty(&self.schema()?).unwrap_or_default()
+            )),
+        }
+    }
+
+    /// The JSON text of a reply, without its fences, or why it doesn't match.
+    pub fn check(&self, text: &str) -> Result<String, String> {
+        let schema = match self.schema() {
+            Some(schema) => schema,
+            None => return Ok(text.to_string()),
+        };
+        let text = tools::strip_fences(text);
+        let value =
+            serde_json::from_str::<Value>(text).map_err(|e| format!("invalid JSON: {}", e))?;
+        validate(&value, &schema, "$")?;
+        Ok(text.to_string())
+    }
+}
+
+fn type_matches(value: &Value, ty: &str) -> bool {
+    match ty {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        _ => true,
+    }
+}
+
+/// Check `value` against the common keywords of a JSON schema: `type`,
+/// `enum`, `const`, `properties`, `required`, `additionalProperties`,
+/// `items` and `anyOf`. The others are ignored.
+pub fn validate(value: &Value, schema: &Value, path: &str) -> Result<(), String> {
+    let schema = match schema {
+        Value::Object(schema) => schema,
+        // `true` or a schema we don't understand
+        _ => return Ok(()),
+    };
+
+    match schema.get("type") {
+        Some(Value::String(ty)) if !type_matches(value, ty) => {
+            return Err(format!("{}: expected {}", path, ty));
+        }
+        Some(Value::Array(types))
+            if !types
+                .iter()
+                .filter_map(Value::as_str)
+                .any(|ty| type_matches(value, ty)) =>
+        {
+            return Err(format!(
+                "{}: expected one of {}",
+                path,
+                Value::Array(types.clone())
+            ));
+        }
+        _ => {}
+    }
+    if let Some(Value::Array(values)) = schema.get("enum") {
+        if !values.contains(value) {
+            return Err(format!(
+                "{}: not one of {}",
+                path,
+                Value::Array(values.clone())
+            ));
+        }
+    }
+    if let Some(expected) = schema.get("const") {
+        if expected != value {
+            return Err(format!("{}: expected {}", path, expected));
+        }
+    }
+    if let Some(Value::Array(schemas)) = schema.get("anyOf") {
+        if !schemas
+            .iter()
+            .any(|schema| validate(value, schema, path).is_ok())
+        {
+            return Err(format!("{}: matches none of anyOf", path));
+        }
+    }
+
+    if let Value::Object(object) = value {
+        let properties = schema.get("properties").and_then(Value::as_object);
+        if let Some(Value::Array(required)) = schema.get("required") {
+            if let Some(missing) = required
+                .iter()
+                .filter_map(Value::as_str)
+                .find(|name| !object.contains_key(*name))
+            {
+                return Err(format!("{}: missing property {}", path, missing));
+            }
+        }
+        for (name, property) in object {
+            match properties.and_then(|properties| properties.get(name)) {
+                Some(property_schema) => {
+                    validate(property, property_schema, &format!("{}.{}", path, name))?
+                }
+                None if schema.get("additionalProperties") == Some(&Value::Bool(false)) => {
+                    return Err(format!("{}: unexpected property {}", path, name));
+                }
+                None => {}
+            }
+        }
+    }
+    if let (Value::Array(items), Some(item_schema)) = (value, schema.get("items")) {
+        for (i, item) in items.iter().enumerate() {
+            validate(item, item_schema, &format!("{}[{}]", path, i))?;
+        }
+    }
+    Ok(())
+}
@@ -1,10 +1,24 @@
 use clap::Parser;
-use std::{io::Write, sync::Arc};
+use std::{io::Write, sync::Arc, time::Duration};
+#[cfg(feature = "proxy")]
+mod anthropic;
 #[cfg(feature = "cli")]
 mod cli;
+#[cfg(feature = "proxy")]
+mod conversations;
 mod fgpt;
 #[cfg(feature = "proxy")]
+mod json_mode;
+#[cfg(feature = "proxy")]
+mod pool;
+#[cfg(feature = "proxy")]
 mod proxy;
+mod sse;
+#[cfg(test)]
+mod tests;
+mod tokens;
+#[cfg(feature = "proxy")]
+mod tools;
 
 #[derive(Parser, Debug)]
 #[command(version)]
@@ -28,6 +42,9 @@ pub(crate) struct Args {
     #[clap(long, short, help = "Via proxy server address")]
     proxy: Option<String>,
 
+    #[clap(long, default_value = fgpt::OPENAI_ENDPOINT, help = "Upstream base URL")]
+    upstream: String,
+
     #[clap(long, help = "The file to write the log to")]
     log_file: Option<String>,
 
@@ -38,6 +55,57 @@ pub(crate) struct Args {
     )]
     log_level: String,
 
+    #[clap(long, default_value = "10", help = "Upstream connect timeout in secs")]
+    connect_timeout: u64,
+
+    #[clap(
+        long,
+        default_value = "60",
+        help = "Upstream read timeout in secs, 0 to disable"
+    )]
+    read_timeout: u64,
+
+    #[clap(
+        long,
+        default_value = "0",
+        help = "Upstream total request timeout in secs, 0 to disable"
+    )]
+    timeout: u64,
+
+    #[clap(
+        long,
+        default_value = "30",
+        help = "TCP and HTTP/2 keep-alive interval in secs, 0 to disable"
+    )]
+    keep_alive: u64,
+
+    #[clap(
+        long,
+        default_value = "90",
+        help = "Close idle upstream connections after secs, 0 to disable"
+    )]
+    pool_idle_timeout: u64,
+
+    #[clap(long, help = "Disable HTTP/2 to the upstream")]
+    http1_only: bool,
+
+    #[clap(long, default_value = "3", help = "Max attempts to open a completion")]
+    retry_attempts: u32,
+
+    #[clap(long, default_value = "500", help = "Base delay between retries in ms")]
+    retry_delay: u64,
+
+    #[clap(long, default_value = "8000", help = "Max delay between retries in ms")]
+    retry_max_delay: u64,
+
+    #[clap(
+        long,
+        default_value = "429,500,502,503,504",
+        value_delimiter = ',',
+        help = "Upstream status codes to retry"
+    )]
+    retry_status: Vec<u16>,
+
     #[cfg(feature = "cli")]
     #[clap(long, short, help = "Result as plain code")]
     code: bool,
@@ -65,10 +133,73 @@ pub(crate) struct Args {
     #[cfg(feature = "proxy")]
     #[clap(long, default_value = "false", help = "Disable CORS access control")]
     disable_cors: bool,
+
+    #[cfg(feature = "proxy")]
+    #[clap(
+        long = "model-alias",
+        help = "Public model name served by the proxy besides gpt-3.5-turbo, as NAME or NAME=UPSTREAM_MODEL, can be repeated"
+    )]
+    model_aliases: Vec<String>,
+
+    #[cfg(feature = "proxy")]
+    #[clap(
+        long,
+        default_value = "2",
+        help = "Sessions to keep ready for the proxy, 0 to disable"
+    )]
+    session_pool_size: usize,
+
+    #[cfg(feature = "proxy")]
+    #[clap(
+        long,
+        default_value = "60",
+        help = "Seconds a ready session stays valid"
+    )]
+    session_ttl: u64,
+
+    #[cfg(feature = "proxy")]
+    #[clap(
+        long,
+        default_value = "15",
+        help = "Seconds between SSE keep-alive comments on idle streams, 0 to disable"
+    )]
+    sse_keep_alive: u64,
+
+    #[cfg(feature = "proxy")]
+    #[clap(
+        long,
+        default_value = "4",
+        help = "Most choices a proxied request can ask with n, each runs its own upstream request"
+    )]
+    max_choices: usize,
+
+    #[cfg(feature = "proxy")]
+    #[clap(
+        long,
+        default_value = "2",
+        help = "Times to ask again for a reply that doesn't match the response_format"
+    )]
+    json_retries: usize,
+
+    #[cfg(feature = "proxy")]
+    #[clap(
+        long,
+        default_value = "0",
+        help = "Seconds to remember upstream conversations, so multi-turn clients only send the new turn upstream, 0 to disable"
+    )]
+    conversation_ttl: u64,
+
+    #[cfg(feature = "proxy")]
+    #[clap(
+        long,
+        default_value = "false",
+        help = "Also serve the Anthropic Messages API at {prefix}/messages"
+    )]
+    anthropic: bool,
 }
 
-impl Into<fgpt::AppState> for Args {
-    fn into(self) -> fgpt::AppState {
+impl From<Args> for fgpt::AppState {
+    fn from(args: Args) -> Self {
         let env_lang = std::env::var("LANG")
             .unwrap_or_else(|_| "en-US".to_string())
             .split('.')
@@ -76,30 +207,90 @@ impl Into<fgpt::AppState> for Args {
             .unwrap_or("en-US")
             .to_string();
 
+        let model = args
+            .model
+            .as_ref()
+            .unwrap_or(&"text-davinci-002-render-sha".to_string())
+            .clone();
+
+        #[cfg(feature = "proxy")]
+        let session_pool = (args.serve.is_some() && args.session_pool_size > 0).then(|| {
+            Arc::new(pool::SessionPool::new(
+                args.session_pool_size,
+                Duration::from_secs(args.session_ttl),
+            ))
+        });
+
+        let secs = |secs: u64| (secs > 0).then(|| Duration::from_secs(secs));
+        let client = fgpt::ClientOptions {
+            connect_timeout: Duration::from_secs(args.connect_timeout),
+            read_timeout: secs(args.read_timeout),
+            timeout: secs(args.timeout),
+            tcp_keepalive: secs(args.keep_alive),
+            pool_idle_timeout: secs(args.pool_idle_timeout),
+            http2: !args.http1_only,
+        }
+        .build(args.proxy.as_deref());
+
         fgpt::AppState {
+            client,
             device_id: uuid::Uuid::new_v4().to_string(),
-            code: self.code,
-            qusetion: self.question.clone(),
-            input_file: self.file.clone(),
-            repl: self.repl,
-            dump_stats: self.stats,
-            proxy: self.proxy.clone(),
-            lang: self.lang.as_ref().unwrap_or(&env_lang).clone(),
-            model: self
-                .model
-                .as_ref()
-                .unwrap_or(&"text-davinci-002-render-sha".to_string())
-                .clone(),
+            code: args.code,
+            qusetion: args.question.clone(),
+            input_file: args.file.clone(),
+            repl: args.repl,
+            dump_stats: args.stats,
+            retry: fgpt::RetryPolicy {
+                max_attempts: args.retry_attempts.max(1),
+                base_delay: Duration::from_millis(args.retry_delay),
+                max_delay: Duration::from_millis(args.retry_max_delay),
+                retry_statuses: args.retry_status.clone(),
+            },
+            proxy: args.proxy.clone(),
+            upstream: args.upstream.trim_end_matches('/').to_string(),
+            lang: args.lang.as_ref().unwrap_or(&env_lang).clone(),
+            model: model.clone(),
 
             #[cfg(feature = "proxy")]
-            prefix: self.prefix.as_ref().unwrap_or(&"/v1".to_string()).clone(),
+            prefix: args.prefix.as_ref().unwrap_or(&"/v1".to_string()).clone(),
+            #[cfg(feature = "proxy")]
+            serve_addr: args.serve.as_ref().unwrap_or(&"".to_string()).clone(),
+            #[cfg(feature = "proxy")]
+            sse_keep_alive: secs(args.sse_keep_alive),
+            #[cfg(feature = "proxy")]
+            metrics: Default::default(),
+            #[cfg(feature = "proxy")]
+            session_pool,
+            #[cfg(feature = "proxy")]
+            max_choices: args.max_choices.max(1),
+            #[cfg(feature = "proxy")]
+            json_retries: args.json_retries,
+            #[cfg(feature = "proxy")]
+            anthropic: args.anthropic,
+            #[cfg(feature = "proxy")]
+            conversations: secs(args.conversation_ttl)
+                .map(|ttl| Arc::new(conversations::ConversationStore::new(ttl))),
             #[cfg(feature = "proxy")]
-            serve_addr: self.serve.as_ref().unwrap_or(&"".to_string()).clone(),
+            model_aliases: {
+                let mut aliases = args
+                    .model_aliases
+                    .iter()
+                    .map(|alias| match alias.split_once('=') {
+                        Some((name, slug)) => (name.trim().to_string(), slug.trim().to_string()),
+                        None => (alias.trim().to_string(), model.clone()),
+                    })
+                    .collect::<Vec<_>>();
+                // clients send the default model unless told otherwise
+                if !aliases.iter().any(|(name, _)| name == "gpt-3.5-turbo") {
+                    aliases.insert(0, ("gpt-3.5-turbo".to_string(), model.clone()));
+                }
+                aliases
+            },
         }
     }
 }
 
-fn init_log(level: &String, is_test: bool, log_file_name: &Option<String>) {
+fn init_log(level: &str, is_test: bool, log_file_name: &Option<String>) {
     let target = match log_file_name
         .as_ref()
         .map(|log_file| {
@@ -122,7 +313,7 @@ fn init_log(level: &String, is_test: bool, log_file_name: &Option<String>) {
                 .file()
                 .unwrap_or("unknown")
                 .split('/')
-                .last()
+                .next_back()
                 .unwrap_or("unknown");
 
             writeln!(
@@ -144,8 +335,8 @@ fn init_log(level: &String, is_test: bool, log_file_name: &Option<String>) {
 #[tokio::main]
 pub async fn main() -> Result<(), crate::fgpt::Error> {
     let args = Args::parse();
-    if args.debug && args.log_level == "" {
-        init_log(&"debug".to_string(), false, &args.log_file);
+    if args.debug && args.log_level.is_empty() {
+        init_log("debug", false, &args.log_file);
     } else {
         init_log(&args.log_level, false, &args.log_file);
     }
@@ -153,7 +344,7 @@ pub async fn main() -> Result<(), crate::fgpt::Error> {
     let state: fgpt::AppStateRef = Arc::new(args.into());
 
     #[cfg(feature = "proxy")]
-    if state.serve_addr != "" {
+    if !state.serve_addr.is_empty() {
         return proxy::serve(state).await;
     }
 
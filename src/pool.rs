@@ -0,0 +1,135 @@
+use crate::fgpt::{alloc_session, AppStateRef, Session};
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::sync::Notify;
+
+/// Sessions allocated ahead of time, with their proof of work solved, so a
+/// completion can skip the sentinel round trip.
+pub struct SessionPool {
+    sessions: Mutex<VecDeque<Session>>,
+    target_size: usize,
+    ttl: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    wakeup: Notify,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionPoolStats {
+    pub size: usize,
+    pub target_size: usize,
+    pub ttl_secs: u64,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl SessionPool {
+    pub fn new(target_size: usize, ttl: Duration) -> Self {
+        Self {
+            sessions: Mutex::new(VecDeque::with_capacity(target_size)),
+            target_size,
+            ttl,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            wakeup: Notify::new(),
+        }
+    }
+
+    fn is_expired(&self, session: &Session) -> bool {
+        session
+            .start_at
+            .elapsed()
+            .map(|elapsed| elapsed >= self.ttl)
+            .unwrap_or(true)
+    }
+
+    fn purge_expired(&self) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let before = sessions.len();
+        sessions.retain(|session| !self.is_expired(session));
+        if sessions.len() < before {
+            log::debug!("session pool: {} expired", before - sessions.len());
+        }
+    }
+
+    /// Take the oldest unexpired session, `None` if the pool is empty.
+    pub fn take(&self) -> Option<Session> {
+        let session = {
+            let mut sessions = self.sessions.lock().unwrap();
+            let mut found = None;
+            while let Some(session) = sessions.pop_front() {
+                if !self.is_expired(&session) {
+                    found = Some(session);
+                    break;
+                }
+            }
+            found
+        };
+        match session {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        self.wakeup.notify_one();
+        session
+    }
+
+    fn put(&self, session: Session) {
+        self.sessions.lock().unwrap().push_back(session);
+    }
+
+    fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    /// How long until the oldest session expires.
+    fn next_expiry(&self) -> Duration {
+        self.sessions
+            .lock()
+            .unwrap()
+            .front()
+            .and_then(|session| session.start_at.elapsed().ok())
+            .map(|elapsed| self.ttl.saturating_sub(elapsed))
+            .unwrap_or(self.ttl)
+    }
+
+    pub fn stats(&self) -> SessionPoolStats {
+        SessionPoolStats {
+            size: self.len(),
+            target_size: self.target_size,
+            ttl_secs: self.ttl.as_secs(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Keep the pool filled up to its target size, runs until the process exits.
+pub async fn refill(state: AppStateRef, pool: Arc<SessionPool>) {
+    loop {
+        pool.purge_expired();
+        if pool.len() < pool.target_size {
+            match state
+                .retry
+                .retry("alloc session", || alloc_session(state.clone()))
+                .await
+            {
+                Ok(session) => pool.put(session),
+                Err(e) => {
+                    log::warn!("session pool: refill failed: {}", e);
+                    tokio::time::sleep(state.retry.max_delay).await;
+                }
+            }
+            continue;
+        }
+        tokio::time::timeout(pool.next_expiry(), pool.wakeup.notified())
+            .await
+            .ok();
+    }
+}
@@ -1,207 +1,1708 @@
-use crate::fgpt::{self, AppStateRef, CompletionEvent, CompletionRequest, Message};
+use crate::{
+    anthropic::{self, MessagesRequest},
+    conversations::{self, ConversationRef, ConversationStore},
+    fgpt::{self, AppStateRef, CompletionEvent, CompletionRequest, Message},
+    json_mode::ResponseFormat,
+    pool,
+    tools::{self, Tool, ToolCall, ToolChoice},
+};
 use axum::{
-    extract::State,
-    response::{sse::Event, IntoResponse, Response, Sse},
-    routing::post,
+    extract::{rejection::JsonRejection, Path, State},
+    http::{header::RETRY_AFTER, HeaderMap, HeaderValue, StatusCode},
+    response::{
+        sse::{Event, KeepAlive},
+        IntoResponse, Response, Sse,
+    },
+    routing::{get, post},
     Json, Router,
 };
 use futures::{Stream, StreamExt};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::{
+    collections::VecDeque,
+    fmt,
     pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     task::{Context, Poll},
     time::UNIX_EPOCH,
 };
 
+/// Counters of the proxy, exposed at `/stats`.
+#[derive(Debug, Default)]
+pub struct ProxyMetrics {
+    client_cancelled: AtomicU64,
+}
+
+/// Notes a request whose client went away before the completion ended: the
+/// handler future or the SSE stream is dropped early, which drops the
+/// upstream response with it.
+struct CancelGuard {
+    state: AppStateRef,
+    request_id: String,
+    armed: bool,
+}
+
+impl CancelGuard {
+    fn new(state: &AppStateRef, request_id: &str) -> Self {
+        Self {
+            state: state.clone(),
+            request_id: request_id.to_string(),
+            armed: true,
+        }
+    }
+
+    fn disarm(&mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        if self.armed {
+            log::info!("client_cancelled request_id:{}", self.request_id);
+            self.state
+                .metrics
+                .client_cancelled
+                .fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
 #[derive(Deserialize, Debug, Serialize, Default)]
 struct OpenAPIClientRequest {
-    messages: Vec<Message>,
+    model: Option<String>,
+    messages: Vec<ChatMessage>,
     stream: Option<bool>,
+    stream_options: Option<StreamOptions>,
+    #[serde(alias = "max_completion_tokens")]
+    max_tokens: Option<usize>,
+    stop: Option<StopSequences>,
+    n: Option<usize>,
+    tools: Option<Vec<Tool>>,
+    tool_choice: Option<ToolChoice>,
+    response_format: Option<ResponseFormat>,
+}
+
+/// A request of the legacy text completions API.
+#[derive(Deserialize, Debug, Serialize, Default)]
+struct LegacyCompletionRequest {
+    model: Option<String>,
+    prompt: Option<Prompt>,
+    /// The text after the completion, for fill-in-the-middle.
+    suffix: Option<String>,
+    stream: Option<bool>,
+    stream_options: Option<StreamOptions>,
+    max_tokens: Option<usize>,
+    stop: Option<StopSequences>,
+    n: Option<usize>,
+    /// Prepend the prompt to the completion.
+    echo: Option<bool>,
+}
+
+#[derive(Deserialize, Debug, Serialize)]
+#[serde(untagged)]
+enum Prompt {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl LegacyCompletionRequest {
+    fn prompts(&self) -> Vec<String> {
+        match self.prompt.as_ref() {
+            Some(Prompt::One(prompt)) => vec![prompt.clone()],
+            Some(Prompt::Many(prompts)) if !prompts.is_empty() => prompts.clone(),
+            _ => vec![String::new()],
+        }
+    }
+
+    /// The upstream message of a prompt, an instruction to fill the gap
+    /// when there is a suffix.
+    fn upstream_message(&self, prompt: &str) -> Message {
+        let content = match self.suffix.as_deref().filter(|suffix| !suffix.is_empty()) {
+            Some(suffix) => format!(
+                "Write the text that goes between the prefix and the suffix below. \
+                Reply with only that text, without repeating the prefix or the suffix \
+                and without code fences.\n\n<prefix>{}</prefix>\n<suffix>{}</suffix>",
+                prompt, suffix
+            ),
+            None => prompt.to_string(),
+        };
+        Message {
+            role: "user".to_string(),
+            content,
+            content_type: None,
+        }
+    }
+}
+
+/// A message of the OpenAI chat API, in any of its shapes.
+#[derive(Deserialize, Debug, Serialize, Clone)]
+struct ChatMessage {
+    role: String,
+    #[serde(default)]
+    content: Option<MessageContent>,
+    /// Only names the function of a legacy `function` message, the upstream
+    /// has no participant names.
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    tool_calls: Option<Vec<ToolCall>>,
+    #[serde(default)]
+    tool_call_id: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Serialize, Clone)]
+#[serde(untagged)]
+enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Deserialize, Debug, Serialize, Clone)]
+struct ContentPart {
+    r#type: String,
+    #[serde(default)]
+    text: Option<String>,
+    #[serde(default)]
+    refusal: Option<String>,
+}
+
+impl ChatMessage {
+    /// The text of the message, only text and refusal parts are supported.
+    fn text(&self, index: usize) -> Result<String, ApiError> {
+        let parts = match self.content.as_ref() {
+            None => return Ok(String::new()),
+            Some(MessageContent::Text(text)) => return Ok(text.clone()),
+            Some(MessageContent::Parts(parts)) => parts,
+        };
+        let texts = parts
+            .iter()
+            .enumerate()
+            .map(|(i, part)| {
+                let param = format!("messages[{}].content[{}]", index, i);
+                let text = match part.r#type.as_str() {
+                    "text" => part.text.as_ref(),
+                    "refusal" => part.refusal.as_ref(),
+                    _ => {
+                        return Err(ApiError::invalid_request(format!(
+                            "unsupported content part type: {}",
+                            part.r#type
+                        ))
+                        .with_param(&format!("{}.type", param)))
+                    }
+                };
+                text.cloned().ok_or_else(|| {
+                    ApiError::invalid_request(format!(
+                        "missing {} of the content part",
+                        part.r#type
+                    ))
+                    .with_param(&format!("{}.{}", param, part.r#type))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(texts.join("\n"))
+    }
+
+    /// The message sent upstream, which only knows system, user and
+    /// assistant messages of plain text.
+    fn to_message(&self, index: usize) -> Result<Message, ApiError> {
+        let content = self.text(index)?;
+        let (role, content) = match self.role.as_str() {
+            "system" | "developer" => ("system", content),
+            "user" => ("user", content),
+            "assistant" => match self.tool_calls.as_deref() {
+                Some(calls) if !calls.is_empty() => {
+                    let calls = tools::format_tool_calls(calls);
+                    match content.is_empty() {
+                        true => ("assistant", calls),
+                        false => ("assistant", format!("{}\n{}", content, calls)),
+                    }
+                }
+                _ => ("assistant", content),
+            },
+            "tool" | "function" => (
+                "user",
+                tools::format_tool_result(
+                    self.tool_call_id.as_deref().or(self.name.as_deref()),
+                    &content,
+                ),
+            ),
+            role => {
+                return Err(ApiError::invalid_request(format!(
+                    "unsupported message role: {}",
+                    role
+                ))
+                .with_param(&format!("messages[{}].role", index)))
+            }
+        };
+        Ok(Message {
+            role: role.to_string(),
+            content,
+            content_type: None,
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Serialize)]
+#[serde(untagged)]
+enum StopSequences {
+    One(String),
+    Many(Vec<String>),
+}
+
+fn completion_limits(
+    max_tokens: Option<usize>,
+    stop: Option<&StopSequences>,
+) -> Result<fgpt::CompletionLimits, ApiError> {
+    if max_tokens == Some(0) {
+        return Err(
+            ApiError::invalid_request("max_tokens must be at least 1").with_param("max_tokens")
+        );
+    }
+    let stop = match stop {
+        Some(StopSequences::One(stop)) => vec![stop.clone()],
+        Some(StopSequences::Many(stop)) => stop.clone(),
+        None => vec![],
+    };
+    Ok(fgpt::CompletionLimits {
+        max_tokens,
+        stop: stop.into_iter().filter(|stop| !stop.is_empty()).collect(),
+    })
+}
+
+impl OpenAPIClientRequest {
+    fn limits(&self) -> Result<fgpt::CompletionLimits, ApiError> {
+        completion_limits(self.max_tokens, self.stop.as_ref())
+    }
+
+    /// The tools the model may call, empty when it must not call any.
+    fn tools(&self) -> Result<Vec<Tool>, ApiError> {
+        let tools = self.tools.clone().unwrap_or_default();
+        if let Some(tool) = tools.iter().find(|tool| tool.r#type != "function") {
+            return Err(ApiError::invalid_request(format!(
+                "unsupported tool type: {}",
+                tool.r#type
+            ))
+            .with_param("tools"));
+        }
+        match self.tool_choice.as_ref() {
+            Some(ToolChoice::Mode(mode)) if mode == "none" => Ok(vec![]),
+            Some(ToolChoice::Mode(mode)) if mode != "auto" && mode != "required" => Err(
+                ApiError::invalid_request(format!("invalid tool_choice: {}", mode))
+                    .with_param("tool_choice"),
+            ),
+            Some(ToolChoice::Function { function })
+                if !tools.iter().any(|tool| tool.function.name == function.name) =>
+            {
+                Err(
+                    ApiError::invalid_request(format!("unknown tool: {}", function.name))
+                        .with_param("tool_choice"),
+                )
+            }
+            _ => Ok(tools),
+        }
+    }
+
+    /// The response format asking for JSON, if any.
+    fn json_format(&self) -> Option<ResponseFormat> {
+        self.response_format
+            .clone()
+            .filter(|format| format.is_json())
+    }
+
+    /// The messages sent upstream, led by the tools and JSON prompts if any.
+    fn upstream_messages(&self, tools: &[Tool]) -> Result<Vec<Message>, ApiError> {
+        let tools_prompt = tools::system_prompt(tools, self.tool_choice.as_ref());
+        let json_prompt = self.json_format().and_then(|format| format.system_prompt());
+        let prompts = tools_prompt.into_iter().chain(json_prompt).map(|content| {
+            Ok(Message {
+                role: "system".to_string(),
+                content,
+                content_type: None,
+            })
+        });
+        prompts
+            .chain(
+                self.messages
+                    .iter()
+                    .enumerate()
+                    .map(|(index, message)| message.to_message(index)),
+            )
+            .collect()
+    }
+}
+
+#[derive(Deserialize, Debug, Serialize, Default)]
+struct StreamOptions {
+    #[serde(default)]
+    include_usage: bool,
+}
+
+/// An error answered in the OpenAI format, so client libraries can apply
+/// their usual retry and error handling.
+#[derive(Debug)]
+struct ApiError {
+    status: StatusCode,
+    message: String,
+    r#type: &'static str,
+    code: Option<&'static str>,
+    param: Option<String>,
+    retry_after: Option<u64>,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, r#type: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+            r#type,
+            code: None,
+            param: None,
+            retry_after: None,
+        }
+    }
+
+    fn invalid_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "invalid_request_error", message)
+    }
+
+    fn upstream(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_GATEWAY, "api_error", message).with_code("upstream_error")
+    }
+
+    fn with_code(mut self, code: &'static str) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    fn with_param(mut self, param: &str) -> Self {
+        self.param = Some(param.to_string());
+        self
+    }
+
+    /// The OpenAI error body, also sent as the last event of a stream.
+    fn body(&self) -> serde_json::Value {
+        json!({
+            "error": {
+                "message": self.message,
+                "type": self.r#type,
+                "code": self.code,
+                "param": self.param,
+            }
+        })
+    }
+
+    /// The error in the Anthropic format, for the Messages API.
+    fn anthropic_body(&self) -> serde_json::Value {
+        anthropic::error_body(anthropic::error_type(self.status.as_u16()), &self.message)
+    }
+
+    fn into_anthropic_response(self) -> Response {
+        let body = self.anthropic_body();
+        let mut resp = (self.status, Json(body)).into_response();
+        if let Some(retry_after) = self.retry_after {
+            resp.headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        resp
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}: {}", self.status, self.r#type, self.message)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = self.body();
+        let mut resp = (self.status, Json(body)).into_response();
+        if let Some(retry_after) = self.retry_after {
+            resp.headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        resp
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(e: JsonRejection) -> Self {
+        ApiError::invalid_request(e.body_text())
+    }
+}
+
+impl From<fgpt::Error> for ApiError {
+    fn from(e: fgpt::Error) -> Self {
+        match e {
+            fgpt::Error::RateLimited { retry_after, .. } => ApiError {
+                retry_after,
+                ..ApiError::new(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "rate_limit_error",
+                    "Upstream rate limit reached, please retry later",
+                )
+                .with_code("rate_limit_exceeded")
+            },
+            fgpt::Error::Upstream { status, body } => {
+                let body = body.chars().take(256).collect::<String>();
+                ApiError::upstream(format!("Upstream returned {}: {}", status, body))
+            }
+            fgpt::Error::UnsupportedRegion(_) => {
+                ApiError::upstream("Upstream does not support the region of the proxy")
+                    .with_code("unsupported_region")
+            }
+            fgpt::Error::Timeout(e) => {
+                ApiError::new(StatusCode::GATEWAY_TIMEOUT, "api_error", e.to_string())
+                    .with_code("timeout")
+            }
+            fgpt::Error::Session(e) => ApiError::from(*e),
+            fgpt::Error::ProofOfWork { .. } => {
+                ApiError::upstream(e.to_string()).with_code("proof_of_work_failed")
+            }
+            fgpt::Error::Reqwest(_)
+            | fgpt::Error::Serde(_)
+            | fgpt::Error::Completion(_)
+            | fgpt::Error::Stream(_) => ApiError::upstream(e.to_string()),
+            fgpt::Error::Io(e) => ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "api_error",
+                e.to_string(),
+            ),
+        }
+    }
+}
+
+// The upstream does not report when a model was published, use the
+// release date of gpt-3.5-turbo for every model we serve.
+const MODEL_CREATED: i64 = 1677610602;
+
+/// All the model ids the proxy accepts: the public aliases first, then the
+/// upstream models they map to.
+fn served_models(state: &AppStateRef) -> Vec<String> {
+    let mut models = state
+        .model_aliases
+        .iter()
+        .map(|(name, _)| name.clone())
+        .collect::<Vec<_>>();
+    let upstream_models =
+        std::iter::once(&state.model).chain(state.model_aliases.iter().map(|(_, slug)| slug));
+    for slug in upstream_models {
+        if !models.contains(slug) {
+            models.push(slug.clone());
+        }
+    }
+    models
+}
+
+/// Map the model asked by the client to the upstream model slug, `None` if
+/// the proxy does not serve it.
+fn resolve_model(state: &AppStateRef, model: Option<&str>) -> Option<String> {
+    let model = match model {
+        Some(model) => model,
+        None => return Some(state.model.clone()),
+    };
+    if let Some((_, slug)) = state.model_aliases.iter().find(|(name, _)| name == model) {
+        return Some(slug.clone());
+    }
+    served_models(state)
+        .into_iter()
+        .find(|served| served == model)
+}
+
+fn model_object(id: &str) -> serde_json::Value {
+    json!({
+        "id": id,
+        "object": "model",
+        "created": MODEL_CREATED,
+        "owned_by": "openai",
+    })
+}
+
+async fn list_models(State(state): State<AppStateRef>) -> Response {
+    let data = served_models(&state)
+        .iter()
+        .map(|id| model_object(id))
+        .collect::<Vec<_>>();
+    Json(json!({
+        "object": "list",
+        "data": data,
+    }))
+    .into_response()
+}
+
+fn model_not_found(id: &str) -> ApiError {
+    ApiError::new(
+        StatusCode::NOT_FOUND,
+        "invalid_request_error",
+        format!("The model `{}` does not exist", id),
+    )
+    .with_code("model_not_found")
+    .with_param("model")
+}
+
+async fn retrieve_model(State(state): State<AppStateRef>, Path(id): Path<String>) -> Response {
+    if served_models(&state).contains(&id) {
+        return Json(model_object(&id)).into_response();
+    }
+    model_not_found(&id).into_response()
+}
+
+/// The header naming the conversation of a request, so its next turns
+/// continue the same upstream conversation.
+const CONVERSATION_HEADER: &str = "x-conversation-id";
+
+/// The body of a request, the upstream model it asks for and the
+/// conversation named by the client.
+fn accept_request<T>(
+    state: &AppStateRef,
+    headers: &HeaderMap,
+    params: Result<Json<T>, JsonRejection>,
+    model: impl Fn(&T) -> Option<&str>,
+) -> Result<(T, String, Option<String>), ApiError> {
+    let Json(params) = params?;
+    let upstream_model = match resolve_model(state, model(&params)) {
+        Some(upstream_model) => upstream_model,
+        None => return Err(model_not_found(model(&params).unwrap_or_default())),
+    };
+    let client_id = headers
+        .get(CONVERSATION_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
+    Ok((params, upstream_model, client_id))
 }
 
 async fn proxy_completions(
     State(state): State<AppStateRef>,
-    Json(params): Json<OpenAPIClientRequest>,
+    headers: HeaderMap,
+    params: Result<Json<OpenAPIClientRequest>, JsonRejection>,
 ) -> Response {
+    let (params, upstream_model, client_id) =
+        match accept_request(&state, &headers, params, |params| params.model.as_deref()) {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                log::warn!("{}", e);
+                return e.into_response();
+            }
+        };
     log::info!(
-        "exec stream:{:?} messages:{:?}",
+        "exec stream:{:?} model:{:?} messages:{:?}",
         params.stream,
+        params.model,
         params.messages
     );
-
-    match handle_proxy_completions(State(state), Json(params)).await {
+    match handle_proxy_completions(State(state), Json(params), upstream_model, client_id).await {
         Ok(resp) => resp,
         Err(e) => {
             log::error!("{}", e);
-            let resp = Response::new(e.to_string().into());
-            let (mut parts, body) = resp.into_parts();
-            parts.status = axum::http::StatusCode::INTERNAL_SERVER_ERROR;
-            Response::from_parts(parts, body)
+            e.into_response()
         }
     }
 }
 
+/// Read a completion until its end, for the non-stream responses.
+async fn read_to_end(stream: &mut fgpt::CompletionStream) -> Result<(), ApiError> {
+    while let Some(event) = stream.next().await {
+        let event = match event {
+            Ok(event) => event,
+            Err(fgpt::Error::Stream(e)) => {
+                log::warn!("{}", e);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        match event {
+            CompletionEvent::Done => {
+                break;
+            }
+            CompletionEvent::Error(reason) => {
+                return Err(fgpt::Error::from_completion(reason).into());
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Usage of the choices of a request, summed as each choice is a
+/// conversation of its own upstream.
+fn usage<'a>(summaries: impl Iterator<Item = &'a fgpt::CompletionSummary>) -> serde_json::Value {
+    let (prompt_tokens, completion_tokens) = summaries.fold((0, 0), |(prompt, completion), s| {
+        (prompt + s.prompt_tokens, completion + s.completion_tokens)
+    });
+    json!({
+        "prompt_tokens": prompt_tokens,
+        "completion_tokens": completion_tokens,
+        "total_tokens": prompt_tokens + completion_tokens
+    })
+}
+
+/// Remembers the upstream conversation of a request once its reply is
+/// complete, by the client id or else by the messages with the reply.
+struct ConversationKey {
+    store: Arc<ConversationStore>,
+    client_id: Option<String>,
+    messages: Vec<Message>,
+}
+
+impl ConversationKey {
+    /// Remember the conversation of a reply, with its `tool_calls` when it
+    /// was parsed into calls.
+    fn remember(&self, summary: &fgpt::CompletionSummary, tool_calls: Option<&[ToolCall]>) {
+        // upstream has the reply in full, the client only what was cut
+        if summary.stop_sequence.is_some() || summary.finish_reason.as_deref() == Some("length") {
+            return;
+        }
+        let (Some(conversation_id), Some(last_message_id)) =
+            (&summary.conversation_id, &summary.last_message_id)
+        else {
+            return;
+        };
+        // the client sends the reply back the way it got it, calls are
+        // written the same as `ChatMessage::to_message` does
+        let content = match tool_calls {
+            Some(calls) => tools::format_tool_calls(calls),
+            None => summary.text.clone(),
+        };
+        let mut messages = self.messages.clone();
+        messages.push(Message {
+            role: "assistant".to_string(),
+            content,
+            content_type: None,
+        });
+        let history = conversations::history_key(&messages);
+        let key = match self.client_id.as_deref() {
+            Some(id) => conversations::client_key(id),
+            None => history.clone(),
+        };
+        self.store.put(
+            key,
+            ConversationRef {
+                conversation_id: conversation_id.clone(),
+                last_message_id: last_message_id.clone(),
+                history,
+            },
+        );
+    }
+}
+
+/// The conversation the messages continue, and the new turn to send it.
+/// Without one, all the messages are sent to a new conversation.
+fn resume_conversation(
+    store: &ConversationStore,
+    client_id: Option<&str>,
+    messages: &[Message],
+) -> (Option<ConversationRef>, Vec<Message>) {
+    // the new turn starts after the last reply
+    let mut turns = (1..messages.len())
+        .rev()
+        .filter(|&start| messages[start - 1].role == "assistant");
+    let resumed = match client_id {
+        // a named conversation goes on only from its last reply, not from
+        // an edited or regenerated one
+        Some(id) => store
+            .get(&conversations::client_key(id))
+            .zip(turns.next())
+            .filter(|(conversation, start)| {
+                conversation.history == conversations::history_key(&messages[..*start])
+            }),
+        None => turns
+            .filter_map(|start| {
+                store
+                    .get(&conversations::history_key(&messages[..start]))
+                    .map(|conversation| (conversation, start))
+            })
+            .next(),
+    };
+    match resumed {
+        Some((conversation, start)) => (Some(conversation), messages[start..].to_vec()),
+        None => (None, messages.to_vec()),
+    }
+}
+
+/// Re-ask upstream until the reply matches the response format, at most
+/// `json_retries` times. The usage of the retries adds up in the summary.
+/// Returns whether the reply differs from the one upstream first sent.
+async fn ensure_json(
+    state: &AppStateRef,
+    messages: &[Message],
+    upstream_model: &str,
+    limits: &fgpt::CompletionLimits,
+    format: &ResponseFormat,
+    summary: &mut fgpt::CompletionSummary,
+) -> Result<bool, ApiError> {
+    let mut retries = 0;
+    loop {
+        let reason = match format.check(&summary.text) {
+            Ok(text) => {
+                let rewritten = retries > 0 || text != summary.text;
+                summary.text = text;
+                return Ok(rewritten);
+            }
+            Err(reason) => reason,
+        };
+        if retries >= state.json_retries {
+            return Err(ApiError::upstream(format!(
+                "the reply does not match response_format after {} retries: {}",
+                retries, reason
+            ))
+            .with_code("invalid_response_format"));
+        }
+        retries += 1;
+        log::warn!(
+            "request_id:{} reply does not match response_format, retry {}: {}",
+            summary.request_id,
+            retries,
+            reason
+        );
+
+        let mut messages = messages.to_vec();
+        messages.push(Message {
+            role: "assistant".to_string(),
+            content: summary.text.clone(),
+            content_type: None,
+        });
+        messages.push(Message {
+            role: "user".to_string(),
+            content: format!(
+                "Your reply is not valid: {}. Reply again with only the JSON.",
+                reason
+            ),
+            content_type: None,
+        });
+        let req = CompletionRequest::new(
+            state.clone(),
+            Some(upstream_model.to_string()),
+            messages,
+            None,
+            Some(uuid::Uuid::new_v4().to_string()),
+        );
+        let mut stream = req.stream(state.clone()).await?;
+        stream.set_limits(limits.clone());
+        read_to_end(&mut stream).await?;
+        let retry = stream.into_summary();
+        summary.prompt_tokens += retry.prompt_tokens;
+        summary.completion_tokens += retry.completion_tokens;
+        summary.finish_reason = retry.finish_reason;
+        summary.text = retry.text;
+        summary.conversation_id = retry.conversation_id;
+        summary.last_message_id = retry.last_message_id;
+    }
+}
+
+/// The chunks of completions already read to the end, in the same sequence
+/// as `CompletionToSSEStream`.
+fn replay_chunks(
+    summary: &fgpt::CompletionSummary,
+    model: &str,
+    choices: &[serde_json::Value],
+    usage: Option<serde_json::Value>,
+) -> Vec<Event> {
+    let chunk = |choices: serde_json::Value| {
+        let mut body = json!({
+            "id": summary.request_id,
+            "created": summary.start_at.duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            "model": model,
+            "object": "chat.completion.chunk",
+            "choices": choices,
+        });
+        if usage.is_some() {
+            body["usage"] = serde_json::Value::Null;
+        }
+        Event::default().data(body.to_string())
+    };
+    let delta = |index: &serde_json::Value, delta: serde_json::Value, finish_reason| {
+        chunk(json!([{"index": index, "finish_reason": finish_reason, "delta": delta}]))
+    };
+
+    let mut events = vec![];
+    for choice in choices {
+        let index = &choice["index"];
+        let message = &choice["message"];
+        events.push(delta(
+            index,
+            json!({"role": "assistant", "content": ""}),
+            None,
+        ));
+        match message["tool_calls"].as_array() {
+            Some(calls) => {
+                for (i, call) in calls.iter().enumerate() {
+                    let mut call = call.clone();
+                    call["index"] = json!(i);
+                    events.push(delta(index, json!({ "tool_calls": [call] }), None));
+                }
+            }
+            None => events.push(delta(index, json!({"content": message["content"]}), None)),
+        }
+        events.push(delta(index, json!({}), choice["finish_reason"].as_str()));
+    }
+    if let Some(usage) = usage {
+        let mut body = json!({
+            "id": summary.request_id,
+            "created": summary.start_at.duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            "model": model,
+            "object": "chat.completion.chunk",
+            "choices": [],
+        });
+        body["usage"] = usage;
+        events.push(Event::default().data(body.to_string()));
+    }
+    events.push(Event::default().data("[DONE]"));
+    events
+}
+
 async fn handle_proxy_completions(
     State(state): State<AppStateRef>,
     Json(params): Json<OpenAPIClientRequest>,
-) -> Result<Response, fgpt::Error> {
+    upstream_model: String,
+    client_id: Option<String>,
+) -> Result<Response, ApiError> {
     let stream_mode = params.stream.unwrap_or(false);
-    let req = CompletionRequest::new(
-        state.clone(),
-        params.messages,
-        None,
-        Some(uuid::Uuid::new_v4().to_string()),
-    );
+    let limits = params.limits()?;
+    let tools = params.tools()?;
+    let messages = params.upstream_messages(&tools)?;
+    let n = params.n.unwrap_or(1);
+    if n == 0 || n > state.max_choices {
+        return Err(ApiError::invalid_request(format!(
+            "n must be between 1 and {}",
+            state.max_choices
+        ))
+        .with_param("n"));
+    }
 
-    let mut stream = req.stream(state.clone()).await?;
-    if !stream_mode {
-        while let Some(Ok(event)) = stream.next().await {
-            match event {
-                CompletionEvent::Done => {
-                    break;
-                }
-                CompletionEvent::Error(reason) => {
-                    return Err(fgpt::Error::Io(reason));
+    // only a single choice can be continued
+    let store = state.conversations.clone().filter(|_| n == 1);
+    let (resume, turn) = match store.as_ref() {
+        Some(store) => resume_conversation(store, client_id.as_deref(), &messages),
+        None => (None, messages.clone()),
+    };
+    if let Some(resume) = resume.as_ref() {
+        log::info!(
+            "continue conversation:{} with {} of {} messages",
+            resume.conversation_id,
+            turn.len(),
+            messages.len()
+        );
+    }
+    let conversation_key = store.map(|store| ConversationKey {
+        store,
+        client_id,
+        messages: messages.clone(),
+    });
+
+    // every choice is a conversation of its own, with its own session
+    let mut streams = futures::future::try_join_all((0..n).map(|_| {
+        let req = CompletionRequest::new(
+            state.clone(),
+            Some(upstream_model.clone()),
+            turn.clone(),
+            resume.as_ref().map(|resume| resume.conversation_id.clone()),
+            Some(
+                resume
+                    .as_ref()
+                    .map(|resume| resume.last_message_id.clone())
+                    .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+            ),
+        );
+        let state = state.clone();
+        async move { req.stream(state).await }
+    }))
+    .await?;
+    streams
+        .iter_mut()
+        .for_each(|stream| stream.set_limits(limits.clone()));
+    let mut cancel_guard = CancelGuard::new(&state, &streams[0].summary().request_id);
+    let json_format = params.json_format();
+    // a JSON reply is only checked once complete, so it is never streamed
+    // as it arrives
+    let include_usage = params
+        .stream_options
+        .as_ref()
+        .map(|options| options.include_usage)
+        .unwrap_or(false);
+    if !stream_mode || json_format.is_some() {
+        let client_model = params.model.clone();
+        let buffered_state = state.clone();
+        let buffered = async move {
+            let state = buffered_state;
+            let result = async {
+                futures::future::try_join_all(streams.iter_mut().map(read_to_end)).await?;
+                let mut summaries = streams
+                    .into_iter()
+                    .map(|stream| stream.into_summary())
+                    .collect::<Vec<_>>();
+                let tool_calls = summaries
+                    .iter()
+                    .map(|summary| {
+                        (!tools.is_empty())
+                            .then(|| tools::parse_tool_calls(&summary.text, &tools))
+                            .flatten()
+                    })
+                    .collect::<Vec<_>>();
+                let mut rewritten = vec![false; summaries.len()];
+                if let Some(format) = json_format.as_ref() {
+                    let (state, messages, upstream_model, limits) =
+                        (&state, &messages, &upstream_model, &limits);
+                    rewritten = futures::future::try_join_all(
+                        summaries.iter_mut().zip(tool_calls.iter()).map(
+                            |(summary, tool_calls)| async move {
+                                match tool_calls {
+                                    Some(_) => Ok(false),
+                                    None => {
+                                        ensure_json(
+                                            state,
+                                            messages,
+                                            upstream_model,
+                                            limits,
+                                            format,
+                                            summary,
+                                        )
+                                        .await
+                                    }
+                                }
+                            },
+                        ),
+                    )
+                    .await?;
                 }
-                _ => {}
+                Ok::<_, ApiError>((summaries, tool_calls, rewritten))
+            }
+            .await;
+            cancel_guard.disarm();
+            let (mut summaries, tool_calls, rewritten) = result?;
+            // the client never sees a rewritten reply as upstream has it
+            if let Some(key) = conversation_key.as_ref().filter(|_| !rewritten[0]) {
+                key.remember(&summaries[0], tool_calls[0].as_deref());
             }
+
+            let summary = &summaries[0];
+            let model = client_model
+                .or_else(|| summary.model_slug.clone())
+                .unwrap_or(upstream_model);
+            let choices = summaries
+                .iter()
+                .zip(tool_calls)
+                .enumerate()
+                .map(|(index, (summary, tool_calls))| match tool_calls {
+                    Some(tool_calls) => json!({
+                        "finish_reason": "tool_calls",
+                        "index": index,
+                        "message": {
+                            "content": null,
+                            "role": "assistant",
+                            "tool_calls": tool_calls
+                        }
+                    }),
+                    None => json!({
+                        "finish_reason": summary.finish_reason,
+                        "index": index,
+                        "message": {
+                            "content": summary.text,
+                            "role": "assistant"
+                        }
+                    }),
+                })
+                .collect::<Vec<_>>();
+            let usage = usage(summaries.iter());
+
+            log::info!(
+                "sync exec request_id:{} choices:{} elapsed:{:.2}s throughput:{:.2} tokens:{}",
+                summary.request_id,
+                summaries.len(),
+                summary.elapsed().as_secs_f64(),
+                usage["completion_tokens"].as_f64().unwrap_or_default()
+                    / summary.elapsed().as_secs_f64(),
+                usage["total_tokens"]
+            );
+            Ok::<_, ApiError>((summaries.swap_remove(0), model, choices, usage))
+        };
+
+        if stream_mode {
+            // the response starts at once, so the keep-alive runs while the
+            // reply is read and checked
+            let events = futures::stream::once(async move {
+                match buffered.await {
+                    Ok((summary, model, choices, usage)) => {
+                        replay_chunks(&summary, &model, &choices, include_usage.then_some(usage))
+                    }
+                    Err(e) => {
+                        log::error!("stream {}", e);
+                        vec![
+                            Event::default().data(e.body().to_string()),
+                            Event::default().data("[DONE]"),
+                        ]
+                    }
+                }
+            })
+            .flat_map(|events| futures::stream::iter(events.into_iter().map(Ok::<_, fgpt::Error>)));
+            return Ok(sse_response(&state, Sse::new(events)));
         }
-        let textbuf = stream.textbuf.borrow().clone();
+
+        let (summary, model, choices, usage) = buffered.await?;
         let body = json!(
             {
-                "id": stream.request_id,
-                "created": stream
+                "id": summary.request_id,
+                "created": summary
                 .start_at
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
-                .as_secs_f64(),
-                "model": "gpt-3.5-turbo",
+                .as_secs(),
+                "model": model,
                 "object": "chat.completion",
-                "choices": [
-                    {
-                        "finish_reason": stream.finish_reason,
-                        "index": 0,
-                        "message": {
-                            "content": textbuf,
-                            "role": "assistant"
-                        }
-                    }
-                ],
-                "usage": {
-                    "prompt_tokens": stream.prompt_tokens,
-                    "completion_tokens": stream.completion_tokens,
-                    "total_tokens": stream.total_tokens()
-                }
+                "choices": choices,
+                "usage": usage
             }
         );
         let resp = Response::new(body.to_string());
         let (mut parts, body) = resp.into_parts();
-        parts.status = axum::http::StatusCode::OK;
+        parts.status = StatusCode::OK;
         parts.headers.insert(
             "content-type",
             axum::http::HeaderValue::from_static("application/json"),
         );
+        return Ok(Response::from_parts(parts, body.into()));
+    }
+    let mut sse = CompletionToSSEStream::new(
+        ChunkKind::Chat,
+        streams,
+        params.model,
+        upstream_model,
+        cancel_guard,
+    );
+    if !tools.is_empty() {
+        // replies are held back while they may be tool calls
+        for choice in sse.choices.iter_mut() {
+            choice.held = Some(String::new());
+        }
+    }
+    sse.tools = tools;
+    sse.conversation_key = conversation_key;
+    sse.include_usage = include_usage;
+    Ok(sse_response(&state, Sse::new(sse)))
+}
+
+fn sse_response<S>(state: &AppStateRef, sse: Sse<S>) -> Response
+where
+    S: Stream<Item = Result<Event, fgpt::Error>> + Send + 'static,
+{
+    // upstream heartbeats are forwarded as the same comment, so they reset
+    // the keep-alive timer
+    match state.sse_keep_alive {
+        Some(interval) => sse
+            .keep_alive(KeepAlive::new().interval(interval).text("ping"))
+            .into_response(),
+        None => sse.into_response(),
+    }
+}
+
+async fn legacy_completions(
+    State(state): State<AppStateRef>,
+    headers: HeaderMap,
+    params: Result<Json<LegacyCompletionRequest>, JsonRejection>,
+) -> Response {
+    let (params, upstream_model, _) =
+        match accept_request(&state, &headers, params, |params| params.model.as_deref()) {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                log::warn!("{}", e);
+                return e.into_response();
+            }
+        };
+    log::info!(
+        "exec legacy stream:{:?} model:{:?} prompt:{:?}",
+        params.stream,
+        params.model,
+        params.prompt
+    );
+    match handle_legacy_completions(State(state), Json(params), upstream_model).await {
+        Ok(resp) => resp,
+        Err(e) => {
+            log::error!("{}", e);
+            e.into_response()
+        }
+    }
+}
+
+/// The id of a text completion, from the id of its upstream request.
+fn text_completion_id(summary: &fgpt::CompletionSummary) -> String {
+    match summary.request_id.strip_prefix("chatcmpl-") {
+        Some(id) => format!("cmpl-{}", id),
+        None => summary.request_id.clone(),
+    }
+}
+
+async fn handle_legacy_completions(
+    State(state): State<AppStateRef>,
+    Json(params): Json<LegacyCompletionRequest>,
+    upstream_model: String,
+) -> Result<Response, ApiError> {
+    let stream_mode = params.stream.unwrap_or(false);
+    let echo = params.echo.unwrap_or(false);
+    let limits = completion_limits(params.max_tokens, params.stop.as_ref())?;
+    let n = params.n.unwrap_or(1);
+    let prompts = params.prompts();
+    let choices = match n {
+        0 => None,
+        n if n > state.max_choices => None,
+        n => n.checked_mul(prompts.len()),
+    };
+    if !matches!(choices, Some(choices) if choices <= state.max_choices) {
+        return Err(ApiError::invalid_request(format!(
+            "n times the number of prompts must be between 1 and {}",
+            state.max_choices
+        ))
+        .with_param("n"));
+    }
+
+    // the choices of each prompt follow each other, as OpenAI orders them
+    let choice_prompts = prompts
+        .iter()
+        .flat_map(|prompt| std::iter::repeat_n(prompt, n))
+        .collect::<Vec<_>>();
+    let mut streams = futures::future::try_join_all(choice_prompts.iter().map(|prompt| {
+        let req = CompletionRequest::new(
+            state.clone(),
+            Some(upstream_model.clone()),
+            vec![params.upstream_message(prompt)],
+            None,
+            Some(uuid::Uuid::new_v4().to_string()),
+        );
+        let state = state.clone();
+        async move { req.stream(state).await }
+    }))
+    .await?;
+    streams
+        .iter_mut()
+        .for_each(|stream| stream.set_limits(limits.clone()));
+    let mut cancel_guard = CancelGuard::new(&state, &streams[0].summary().request_id);
+
+    if !stream_mode {
+        let result = futures::future::try_join_all(streams.iter_mut().map(read_to_end)).await;
+        cancel_guard.disarm();
+        result?;
+        let summaries = streams
+            .into_iter()
+            .map(|stream| stream.into_summary())
+            .collect::<Vec<_>>();
+        let summary = &summaries[0];
+        let choices = summaries
+            .iter()
+            .zip(choice_prompts.iter())
+            .enumerate()
+            .map(|(index, (summary, prompt))| {
+                let text = match echo {
+                    true => format!("{}{}", prompt, summary.text),
+                    false => summary.text.clone(),
+                };
+                json!({
+                    "text": text,
+                    "index": index,
+                    "logprobs": null,
+                    "finish_reason": summary.finish_reason,
+                })
+            })
+            .collect::<Vec<_>>();
+        let usage = usage(summaries.iter());
 
         log::info!(
-            "sync exec request_id:{} elapsed:{:.2}s throughput:{:.2} tokens:{}",
-            stream.request_id,
-            stream.start_at.elapsed().unwrap().as_secs_f64(),
-            *stream.completion_tokens.borrow() as f64
-                / stream.start_at.elapsed().unwrap().as_secs_f64(),
-            stream.total_tokens()
+            "sync exec legacy request_id:{} choices:{} elapsed:{:.2}s tokens:{}",
+            summary.request_id,
+            summaries.len(),
+            summary.elapsed().as_secs_f64(),
+            usage["total_tokens"]
         );
+        let body = json!(
+            {
+                "id": text_completion_id(summary),
+                "created": summary
+                .start_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+                "model": params
+                .model
+                .clone()
+                .or_else(|| summary.model_slug.clone())
+                .unwrap_or(upstream_model),
+                "object": "text_completion",
+                "choices": choices,
+                "usage": usage
+            }
+        );
+        return Ok(Json(body).into_response());
+    }
 
-        return Ok(Response::from_parts(parts, body.into()));
+    let mut sse = CompletionToSSEStream::new(
+        ChunkKind::Text,
+        streams,
+        params.model,
+        upstream_model,
+        cancel_guard,
+    );
+    sse.include_usage = params
+        .stream_options
+        .map(|options| options.include_usage)
+        .unwrap_or(false);
+    if echo {
+        for (index, prompt) in choice_prompts.iter().enumerate() {
+            sse.push_delta(index, json!({ "content": prompt }), None);
+        }
     }
-    return Ok(Sse::new(CompletionToSSEStream { stream }).into_response());
+    Ok(sse_response(&state, Sse::new(sse)))
 }
-struct CompletionToSSEStream {
+
+async fn anthropic_messages(
+    State(state): State<AppStateRef>,
+    headers: HeaderMap,
+    params: Result<Json<MessagesRequest>, JsonRejection>,
+) -> Response {
+    let (params, upstream_model, client_id) =
+        match accept_request(&state, &headers, params, |params| params.model.as_deref()) {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                log::warn!("{}", e);
+                return e.into_anthropic_response();
+            }
+        };
+    log::info!(
+        "exec messages stream:{:?} model:{:?} messages:{:?}",
+        params.stream,
+        params.model,
+        params.messages
+    );
+    match handle_anthropic_messages(State(state), Json(params), upstream_model, client_id).await {
+        Ok(resp) => resp,
+        Err(e) => {
+            log::error!("{}", e);
+            e.into_anthropic_response()
+        }
+    }
+}
+
+async fn handle_anthropic_messages(
+    State(state): State<AppStateRef>,
+    Json(params): Json<MessagesRequest>,
+    upstream_model: String,
+    client_id: Option<String>,
+) -> Result<Response, ApiError> {
+    let stream_mode = params.stream.unwrap_or(false);
+    let stop = params.stop_sequences.clone().map(StopSequences::Many);
+    let limits = completion_limits(params.max_tokens, stop.as_ref())?;
+    let messages = params
+        .upstream_messages()
+        .map_err(|e| ApiError::invalid_request(e.message).with_param(&e.param))?;
+
+    let store = state.conversations.clone();
+    let (resume, turn) = match store.as_ref() {
+        Some(store) => resume_conversation(store, client_id.as_deref(), &messages),
+        None => (None, messages.clone()),
+    };
+    let conversation_key = store.map(|store| ConversationKey {
+        store,
+        client_id,
+        messages,
+    });
+
+    let req = CompletionRequest::new(
+        state.clone(),
+        Some(upstream_model.clone()),
+        turn,
+        resume.as_ref().map(|resume| resume.conversation_id.clone()),
+        Some(
+            resume
+                .map(|resume| resume.last_message_id)
+                .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+        ),
+    );
+    let mut stream = req.stream(state.clone()).await?;
+    stream.set_limits(limits);
+    let mut cancel_guard = CancelGuard::new(&state, &stream.summary().request_id);
+
+    if !stream_mode {
+        let result = read_to_end(&mut stream).await;
+        cancel_guard.disarm();
+        result?;
+        let summary = stream.into_summary();
+        if let Some(key) = conversation_key.as_ref() {
+            key.remember(&summary, None);
+        }
+        let model = params
+            .model
+            .clone()
+            .or_else(|| summary.model_slug.clone())
+            .unwrap_or(upstream_model);
+        log::info!(
+            "sync exec messages request_id:{} elapsed:{:.2}s tokens:{}",
+            summary.request_id,
+            summary.elapsed().as_secs_f64(),
+            summary.total_tokens()
+        );
+        return Ok(Json(anthropic::message_response(&summary, &model)).into_response());
+    }
+
+    let mut sse = CompletionToSSEStream::new(
+        ChunkKind::Messages,
+        vec![stream],
+        params.model,
+        upstream_model,
+        cancel_guard,
+    );
+    sse.conversation_key = conversation_key;
+    Ok(sse_response(&state, Sse::new(sse)))
+}
+
+/// The API the chunks are answered in.
+#[derive(Clone, Copy, PartialEq)]
+enum ChunkKind {
+    Chat,
+    /// The legacy text completions.
+    Text,
+    /// The Anthropic Messages API, with a single choice.
+    Messages,
+}
+
+struct Choice {
     stream: fgpt::CompletionStream,
+    role_sent: bool,
+    /// Its finish chunk is queued.
+    finished: bool,
+    /// The reply held back while it may be a tool call, `None` once it is
+    /// streamed as content.
+    held: Option<String>,
+    /// The calls the held reply was parsed into.
+    tool_calls: Option<Vec<ToolCall>>,
+}
+
+/// Replays the completions as OpenAI chunks: for every choice the role
+/// first, then the content deltas and a chunk with the finish reason. The
+/// choices are interleaved as their deltas arrive, `[DONE]` ends them all.
+struct CompletionToSSEStream {
+    choices: Vec<Choice>,
+    kind: ChunkKind,
+    /// The model asked by the client, echoed back in every chunk.
+    model: Option<String>,
+    upstream_model: String,
+    tools: Vec<Tool>,
+    conversation_key: Option<ConversationKey>,
+    cancel_guard: CancelGuard,
+    /// Send `usage: null` in every chunk and a usage-only chunk at the end.
+    include_usage: bool,
+    /// The choice polled first, so a fast one doesn't starve the others.
+    next: usize,
+    /// The closing chunks are queued, nothing more to read from upstream.
+    finished: bool,
+    pending: VecDeque<Event>,
+}
+
+impl CompletionToSSEStream {
+    /// The stream of plain replies, without tools, conversation or usage.
+    fn new(
+        kind: ChunkKind,
+        streams: Vec<fgpt::CompletionStream>,
+        model: Option<String>,
+        upstream_model: String,
+        cancel_guard: CancelGuard,
+    ) -> Self {
+        Self {
+            choices: streams
+                .into_iter()
+                .map(|stream| Choice {
+                    stream,
+                    role_sent: false,
+                    finished: false,
+                    held: None,
+                    tool_calls: None,
+                })
+                .collect(),
+            kind,
+            model,
+            upstream_model,
+            tools: vec![],
+            conversation_key: None,
+            cancel_guard,
+            include_usage: false,
+            next: 0,
+            finished: false,
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn summary(&self) -> &fgpt::CompletionSummary {
+        self.choices[0].stream.summary()
+    }
+
+    fn model(&self) -> String {
+        self.model
+            .clone()
+            .or_else(|| self.summary().model_slug.clone())
+            .unwrap_or_else(|| self.upstream_model.clone())
+    }
+
+    fn chunk(&self, choices: serde_json::Value) -> serde_json::Value {
+        let summary = self.summary();
+        let (id, object) = match self.kind {
+            ChunkKind::Chat | ChunkKind::Messages => {
+                (summary.request_id.clone(), "chat.completion.chunk")
+            }
+            ChunkKind::Text => (text_completion_id(summary), "text_completion"),
+        };
+        json!(
+            {
+                "id": id,
+                "created": summary
+                .start_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+                "model": self.model(),
+                "object": object,
+                "choices": choices,
+            }
+        )
+    }
+
+    fn push_delta(&mut self, index: usize, delta: serde_json::Value, finish_reason: Option<&str>) {
+        let choice = match self.kind {
+            ChunkKind::Chat => json!({
+                "index": index,
+                "finish_reason": finish_reason,
+                "delta": delta,
+            }),
+            // no role chunk, the text is sent as is
+            ChunkKind::Text if delta.get("role").is_some() => return,
+            ChunkKind::Text => json!({
+                "index": index,
+                "text": delta["content"].as_str().unwrap_or_default(),
+                "logprobs": null,
+                "finish_reason": finish_reason,
+            }),
+            ChunkKind::Messages => {
+                let events =
+                    anthropic::stream_events(self.summary(), &self.model(), &delta, finish_reason);
+                self.pending.extend(
+                    events
+                        .into_iter()
+                        .map(|(name, data)| Event::default().event(name).data(data.to_string())),
+                );
+                return;
+            }
+        };
+        let mut body = self.chunk(json!([choice]));
+        if self.include_usage {
+            body["usage"] = serde_json::Value::Null;
+        }
+        self.pending
+            .push_back(Event::default().data(body.to_string()));
+    }
+
+    fn push_role(&mut self, index: usize) {
+        if !self.choices[index].role_sent {
+            self.choices[index].role_sent = true;
+            self.push_delta(index, json!({"role": "assistant", "content": ""}), None);
+        }
+    }
+
+    /// Queue the finish chunk of a choice.
+    fn push_finish(&mut self, index: usize) {
+        if self.choices[index].finished {
+            return;
+        }
+        self.choices[index].finished = true;
+        self.push_role(index);
+        let mut finish_reason = self.finish_reason(index);
+        if let Some(held) = self.choices[index].held.take() {
+            match tools::parse_tool_calls(&held, &self.tools) {
+                Some(calls) => {
+                    for (i, call) in calls.iter().enumerate() {
+                        let mut call = serde_json::to_value(call).unwrap_or_default();
+                        call["index"] = json!(i);
+                        self.push_delta(index, json!({ "tool_calls": [call] }), None);
+                    }
+                    self.choices[index].tool_calls = Some(calls);
+                    finish_reason = "tool_calls".to_string();
+                }
+                None if !held.is_empty() => {
+                    self.push_delta(index, json!({ "content": held }), None)
+                }
+                None => {}
+            }
+        }
+        self.push_delta(index, json!({}), Some(&finish_reason));
+    }
+
+    fn finish_reason(&self, index: usize) -> String {
+        self.choices[index]
+            .stream
+            .summary()
+            .finish_reason
+            .clone()
+            .unwrap_or_else(|| "stop".to_string())
+    }
+
+    /// Queue the usage and `[DONE]` once every choice is finished.
+    fn push_done(&mut self) {
+        self.finished = true;
+        if let Some(key) = self.conversation_key.as_ref() {
+            key.remember(self.summary(), self.choices[0].tool_calls.as_deref());
+        }
+        let summaries = self.choices.iter().map(|choice| choice.stream.summary());
+        let usage = usage(summaries);
+        if self.kind == ChunkKind::Messages {
+            let body = json!({"type": "message_stop"});
+            self.pending.push_back(
+                Event::default()
+                    .event("message_stop")
+                    .data(body.to_string()),
+            );
+        } else {
+            if self.include_usage {
+                let mut body = self.chunk(json!([]));
+                body["usage"] = usage.clone();
+                self.pending
+                    .push_back(Event::default().data(body.to_string()));
+            }
+            self.pending.push_back(Event::default().data("[DONE]"));
+        }
+
+        let summary = self.summary();
+        log::info!(
+            "async exec request_id:{} choices:{} elapsed:{:.2}s throughput:{:.2} tokens:{}",
+            summary.request_id,
+            self.choices.len(),
+            summary.elapsed().as_secs_f64(),
+            usage["completion_tokens"].as_f64().unwrap_or_default()
+                / summary.elapsed().as_secs_f64(),
+            usage["total_tokens"]
+        );
+    }
+
+    /// End the stream with an error event, the other choices are dropped
+    /// and no conversation is remembered.
+    fn push_error(&mut self, e: ApiError) {
+        log::error!("stream request_id:{} {}", self.summary().request_id, e);
+        self.finished = true;
+        match self.kind {
+            // the Messages API ends the stream at the error event
+            ChunkKind::Messages => self.pending.push_back(
+                Event::default()
+                    .event("error")
+                    .data(e.anthropic_body().to_string()),
+            ),
+            ChunkKind::Chat | ChunkKind::Text => {
+                self.pending
+                    .push_back(Event::default().data(e.body().to_string()));
+                self.pending.push_back(Event::default().data("[DONE]"));
+            }
+        }
+    }
+
+    fn handle_event(&mut self, index: usize, event: CompletionEvent) {
+        match event {
+            CompletionEvent::Data(data) => {
+                self.push_role(index);
+                let mut content = data.delta_chars.unwrap_or_default();
+                if let Some(held) = self.choices[index].held.as_mut() {
+                    held.push_str(&content);
+                    if tools::may_be_tool_call(held) {
+                        return;
+                    }
+                    content = self.choices[index].held.take().unwrap_or_default();
+                }
+                if !content.is_empty() {
+                    self.push_delta(index, json!({"content": content}), None);
+                }
+            }
+            CompletionEvent::Done => {
+                self.push_finish(index);
+            }
+            CompletionEvent::Error(reason) => {
+                self.push_error(fgpt::Error::from_completion(reason).into());
+            }
+            CompletionEvent::Heartbeat => {
+                // keeps the client connection busy while upstream thinks
+                self.pending.push_back(Event::default().comment("ping"));
+            }
+        }
+    }
 }
 
 impl Stream for CompletionToSSEStream {
-    type Item = reqwest::Result<Event>;
+    type Item = Result<Event, fgpt::Error>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let stream = &mut self.stream;
-        let poll = stream.poll_next_unpin(cx);
-        match poll {
-            Poll::Ready(Some(Ok(event))) => match event {
-                CompletionEvent::Data(data) => {
-                    let body = json!(
-                        {
-                            "id": stream.request_id,
-                            "created":stream
-                            .start_at
-                            .duration_since(UNIX_EPOCH)
-                            .unwrap()
-                            .as_secs_f64(),
-                            "model": "gpt-3.5-turbo",
-                            "object": "chat.completion.chunk",
-                            "choices": [
-                                {
-                                    "index": 0,
-                                    "finish_reason": stream.finish_reason,
-                                    "delta": {
-                                        "content": data.delta_chars,
-                                        "role": "assistant"
-                                    }
-                                }
-                            ],
-                        }
-                    );
-                    let event = Event::default().data(body.to_string());
-                    Poll::Ready(Some(Ok(event)))
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                if self.finished && self.pending.is_empty() {
+                    self.cancel_guard.disarm();
                 }
-                CompletionEvent::Done => {
-                    let completion_tokens = *stream.completion_tokens.borrow();
-                    let total_tokens = completion_tokens + stream.prompt_tokens;
-                    log::info!(
-                        "async exec request_id:{} elapsed:{:.2}s throughput:{:.2} tokens:{}",
-                        stream.request_id,
-                        stream.start_at.elapsed().unwrap().as_secs_f64(),
-                        completion_tokens as f64 / stream.start_at.elapsed().unwrap().as_secs_f64(),
-                        total_tokens
-                    );
-                    Poll::Ready(None)
+                return Poll::Ready(Some(Ok(event)));
+            }
+            if self.finished {
+                return Poll::Ready(None);
+            }
+            if self.choices.iter().all(|choice| choice.finished) {
+                self.push_done();
+                continue;
+            }
+
+            let (count, next) = (self.choices.len(), self.next);
+            let mut polled = None;
+            for index in (0..count).map(|offset| (next + offset) % count) {
+                if self.choices[index].finished {
+                    continue;
                 }
-                CompletionEvent::Error(reason) => {
-                    let body = json!(
-                        {
-                            "id": stream.request_id,
-                            "created": stream.start_at.duration_since(UNIX_EPOCH).unwrap().as_secs_f64(),
-                            "model": "gpt-3.5-turbo",
-                            "object": "chat.completion.chunk",
-                            "choices": [
-                                {
-                                    "index": 0,
-                                    "finish_reason": "error",
-                                    "delta": {
-                                        "content": reason,
-                                    }
-                                }
-                            ],
-                        }
-                    );
-                    let event = Event::default().data(body.to_string());
-                    Poll::Ready(Some(Ok(event)))
+                if let Poll::Ready(poll) = self.choices[index].stream.poll_next_unpin(cx) {
+                    polled = Some((index, poll));
+                    break;
                 }
-                _ => Poll::Pending,
-            },
-            Poll::Ready(Some(Err(event))) => Poll::Ready(Some(Err(event))),
-            Poll::Ready(None) => Poll::Ready(None),
-            Poll::Pending => Poll::Pending,
+            }
+            let (index, poll) = match polled {
+                Some(polled) => polled,
+                None => return Poll::Pending,
+            };
+            self.next = (index + 1) % count;
+            match poll {
+                Some(Ok(event)) => self.handle_event(index, event),
+                Some(Err(fgpt::Error::Stream(e))) => log::warn!("{}", e),
+                Some(Err(e)) => self.push_error(e.into()),
+                None => {
+                    // the upstream closed without its own [DONE]
+                    self.push_finish(index);
+                }
+            }
         }
     }
 }
 
+async fn stats(State(state): State<AppStateRef>) -> Response {
+    Json(json!({
+        "session_pool": state.session_pool.as_ref().map(|pool| pool.stats()),
+        "conversations": state.conversations.as_ref().map(|store| store.stats()),
+        "client_cancelled": state.metrics.client_cancelled.load(Ordering::Relaxed),
+    }))
+    .into_response()
+}
+
 pub async fn serve(state: AppStateRef) -> Result<(), fgpt::Error> {
+    if let Some(pool) = state.session_pool.as_ref() {
+        tokio::spawn(pool::refill(state.clone(), pool.clone()));
+    }
+
+    let mut api = Router::new()
+        .route("/chat/completions", post(proxy_completions))
+        .route("/completions", post(legacy_completions))
+        .route("/models", get(list_models))
+        .route("/models/:id", get(retrieve_model));
+    if state.anthropic {
+        api = api.route("/messages", post(anthropic_messages));
+    }
     let app = Router::new()
-        .nest(
-            &state.prefix,
-            Router::new().route("/chat/completions", post(proxy_completions)),
-        )
+        .route("/stats", get(stats))
+        .nest(&state.prefix, api)
         .with_state(state.clone());
 
     let listener = tokio::net::TcpListener::bind(&state.serve_addr).await?;
@@ -212,6 +1713,10 @@ pub async fn serve(state: AppStateRef) -> Result<(), fgpt::Error> {
     println!("🚀 Server is running at http://{}", state.serve_addr);
     println!("Base URL: http://{}/v1", state.serve_addr);
     println!("Endpoint: http://{}/v1/chat/completions", state.serve_addr);
+    if state.anthropic {
+        println!("Anthropic: http://{}/v1/messages", state.serve_addr);
+    }
+    println!("Models: {}", served_models(&state).join(", "));
 
     axum::serve(listener, app).await.map_err(|e| e.into())
 }
@@ -0,0 +1,134 @@
+use crate::fgpt::Error;
+use bytes::{Buf, BytesMut};
+
+/// One event of a `text/event-stream`, see
+/// https://html.spec.whatwg.org/multipage/server-sent-events.html
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SseFrame {
+    pub event: Option<String>,
+    pub id: Option<String>,
+    pub retry: Option<u64>,
+    pub data: String,
+}
+
+/// Incremental decoder: push the bytes as they arrive, then drain the
+/// complete frames. Lines are only decoded once complete, so UTF-8
+/// sequences split across chunks are fine.
+#[derive(Default)]
+pub struct SseDecoder {
+    buffer: BytesMut,
+    frame: SseFrame,
+    has_data: bool,
+    /// Skip the lines up to the next blank line, after a malformed one.
+    discard: bool,
+    started: bool,
+    eof: bool,
+}
+
+impl SseDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, chunk: &[u8]) {
+        self.buffer.extend_from_slice(chunk);
+    }
+
+    /// No more bytes will come, the trailing frame is dispatched even
+    /// without its blank line.
+    pub fn finish(&mut self) {
+        self.eof = true;
+    }
+
+    /// Split the next complete line, without its terminator.
+    fn next_line(&mut self) -> Option<BytesMut> {
+        let pos = self.buffer.iter().position(|b| *b == b'\n' || *b == b'\r');
+        match pos {
+            Some(pos) => {
+                // a CR at the end of the buffer may be the first half of CRLF
+                if self.buffer[pos] == b'\r' && pos + 1 == self.buffer.len() && !self.eof {
+                    return None;
+                }
+                let line = self.buffer.split_to(pos);
+                let crlf = self.buffer[0] == b'\r' && self.buffer.get(1) == Some(&b'\n');
+                self.buffer.advance(if crlf { 2 } else { 1 });
+                Some(line)
+            }
+            None if self.eof && !self.buffer.is_empty() => Some(self.buffer.split()),
+            None => None,
+        }
+    }
+
+    fn dispatch(&mut self) -> Option<SseFrame> {
+        let mut frame = std::mem::take(&mut self.frame);
+        let has_data = std::mem::replace(&mut self.has_data, false);
+        if !has_data {
+            return None;
+        }
+        if frame.data.ends_with('\n') {
+            frame.data.pop();
+        }
+        Some(frame)
+    }
+
+    /// The next complete frame, `None` when more bytes are needed.
+    pub fn next_frame(&mut self) -> Option<Result<SseFrame, Error>> {
+        while let Some(line) = self.next_line() {
+            let line = match std::str::from_utf8(&line) {
+                Ok(line) => line,
+                Err(e) => {
+                    self.frame = SseFrame::default();
+                    self.has_data = false;
+                    self.discard = true;
+                    return Some(Err(Error::Stream(format!("invalid utf-8 in frame: {}", e))));
+                }
+            };
+            if self.discard {
+                self.discard = !line.is_empty();
+                continue;
+            }
+            let line = match self.started {
+                true => line,
+                false => {
+                    self.started = true;
+                    line.strip_prefix('\u{feff}').unwrap_or(line)
+                }
+            };
+
+            if line.is_empty() {
+                match self.dispatch() {
+                    Some(frame) => return Some(Ok(frame)),
+                    None => continue,
+                }
+            }
+            if line.starts_with(':') {
+                // comment, used as keep-alive
+                continue;
+            }
+
+            let (field, value) = match line.split_once(':') {
+                Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+                None => (line, ""),
+            };
+            match field {
+                "data" => {
+                    self.frame.data.push_str(value);
+                    self.frame.data.push('\n');
+                    self.has_data = true;
+                }
+                "event" => self.frame.event = Some(value.to_string()),
+                "id" if !value.contains('\0') => self.frame.id = Some(value.to_string()),
+                "retry" => {
+                    if let Ok(retry) = value.parse() {
+                        self.frame.retry = Some(retry);
+                    }
+                }
+                _ => log::debug!("sse: ignore field {:?}", field),
+            }
+        }
+        if self.eof {
+            return self.dispatch().map(Ok);
+        }
+        None
+    }
+}
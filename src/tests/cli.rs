@@ -0,0 +1,32 @@
+use super::{
+    mock::{MockOptions, MockUpstream},
+    test_state,
+};
+use crate::cli;
+
+#[tokio::test]
+async fn test_cli_question() {
+    let mock = MockUpstream::start(MockOptions::default()).await;
+    let state = test_state(&mock.url, &["--code", "write a hello world"]);
+    cli::run_with_input(state, Some("fn main() {}".to_string()))
+        .await
+        .unwrap();
+
+    let body = mock.last_conversation().unwrap();
+    let messages = body["messages"].as_array().unwrap();
+    assert_eq!(messages[0]["author"]["role"], "system");
+    assert_eq!(messages[1]["content"]["parts"][0], "write a hello world");
+    assert_eq!(messages[2]["content"]["parts"][0], "fn main() {}");
+}
+
+#[tokio::test]
+async fn test_cli_upstream_error() {
+    let mock = MockUpstream::start(MockOptions {
+        error: Some("Something went wrong".to_string()),
+        ..Default::default()
+    })
+    .await;
+    let state = test_state(&mock.url, &["hi"]);
+    let e = cli::run_with_input(state, None).await.err().unwrap();
+    assert!(matches!(e, crate::fgpt::Error::Completion(_)), "{:?}", e);
+}
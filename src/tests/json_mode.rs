@@ -0,0 +1,68 @@
+use crate::json_mode::{validate, ResponseFormat};
+use serde_json::json;
+
+fn schema_format(schema: serde_json::Value) -> ResponseFormat {
+    serde_json::from_value(json!({
+        "type": "json_schema",
+        "json_schema": {"name": "test", "schema": schema},
+    }))
+    .unwrap()
+}
+
+#[test]
+fn test_validate() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "city": {"type": "string"},
+            "days": {"type": "integer"},
+            "unit": {"enum": ["C", "F"]},
+            "tags": {"type": "array", "items": {"type": "string"}},
+        },
+        "required": ["city"],
+        "additionalProperties": false,
+    });
+    let ok = json!({"city": "Paris", "days": 3, "unit": "C", "tags": ["a"]});
+    assert_eq!(validate(&ok, &schema, "$"), Ok(()));
+
+    let cases = [
+        (json!([]), "$: expected object"),
+        (json!({"days": 1}), "$: missing property city"),
+        (json!({"city": 1}), "$.city: expected string"),
+        (
+            json!({"city": "a", "days": 1.5}),
+            "$.days: expected integer",
+        ),
+        (
+            json!({"city": "a", "unit": "K"}),
+            r#"$.unit: not one of ["C","F"]"#,
+        ),
+        (
+            json!({"city": "a", "tags": [1]}),
+            "$.tags[0]: expected string",
+        ),
+        (json!({"city": "a", "x": 1}), "$: unexpected property x"),
+    ];
+    for (value, error) in cases {
+        assert_eq!(validate(&value, &schema, "$"), Err(error.to_string()));
+    }
+}
+
+#[test]
+fn test_response_format_check() {
+    let format: ResponseFormat = serde_json::from_value(json!({"type": "json_object"})).unwrap();
+    assert_eq!(
+        format.check("```json\n{\"a\": 1}\n```"),
+        Ok("{\"a\": 1}".to_string())
+    );
+    assert!(format.check("[1, 2]").is_err());
+    assert!(format.check("Sure! {\"a\": 1}").is_err());
+
+    let format = schema_format(json!({"type": "object", "required": ["a"]}));
+    assert!(format.check("{\"a\": 1}").is_ok());
+    assert!(format.check("{\"b\": 1}").is_err());
+
+    let format: ResponseFormat = serde_json::from_value(json!({"type": "text"})).unwrap();
+    assert!(!format.is_json());
+    assert_eq!(format.check("hi"), Ok("hi".to_string()));
+}
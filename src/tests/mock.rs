@@ -0,0 +1,231 @@
+//! A stand-in for the upstream `backend-anon` endpoints, so the tests can
+//! drive the cli, the proxy and `CompletionStream` without network access.
+use axum::{
+    body::Body,
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::post,
+    Json, Router,
+};
+use bytes::Bytes;
+use futures::StreamExt;
+use serde_json::{json, Value};
+use std::{
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+pub const MOCK_MODEL: &str = "text-davinci-002-render-sha";
+
+/// What the mock answers, and the faults it injects.
+#[derive(Clone, Debug)]
+pub struct MockOptions {
+    pub reply: String,
+    /// Send a heartbeat timestamp after every message frame.
+    pub heartbeats: bool,
+    /// Split the body in chunks of this many bytes instead of one per frame.
+    pub chunk_size: Option<usize>,
+    /// Pause before sending every chunk.
+    pub chunk_delay: Option<Duration>,
+    /// Answer the conversation with this status the first `n` times.
+    pub fail_status: Option<(u16, usize)>,
+    /// Answer the sentinel with this status.
+    pub sentinel_status: Option<u16>,
+    /// Send a frame that is not JSON after the first message.
+    pub malformed: bool,
+    /// Send an error payload instead of the final message.
+    pub error: Option<String>,
+    /// Leave out the finished message, with its finish details.
+    pub unfinished: bool,
+    /// Close the body without `[DONE]`.
+    pub no_done: bool,
+}
+
+impl Default for MockOptions {
+    fn default() -> Self {
+        Self {
+            reply: "Hello from the mock upstream, how can I help you today?".to_string(),
+            heartbeats: false,
+            chunk_size: None,
+            chunk_delay: None,
+            fail_status: None,
+            sentinel_status: None,
+            malformed: false,
+            error: None,
+            unfinished: false,
+            no_done: false,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct MockState {
+    pub options: MockOptions,
+    pub sentinel_calls: AtomicUsize,
+    pub conversation_calls: AtomicUsize,
+    pub conversations: Mutex<Vec<Value>>,
+    pub headers: Mutex<Vec<HeaderMap>>,
+}
+
+pub struct MockUpstream {
+    pub url: String,
+    pub state: Arc<MockState>,
+}
+
+impl MockUpstream {
+    pub async fn start(options: MockOptions) -> Self {
+        let state = Arc::new(MockState {
+            options,
+            ..Default::default()
+        });
+        let app = Router::new()
+            .route("/backend-anon/sentinel/chat-requirements", post(sentinel))
+            .route("/backend-anon/conversation", post(conversation))
+            .with_state(state.clone());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        Self { url, state }
+    }
+
+    pub fn conversation_calls(&self) -> usize {
+        self.state.conversation_calls.load(Ordering::SeqCst)
+    }
+
+    pub fn sentinel_calls(&self) -> usize {
+        self.state.sentinel_calls.load(Ordering::SeqCst)
+    }
+
+    pub fn last_conversation(&self) -> Option<Value> {
+        self.state.conversations.lock().unwrap().last().cloned()
+    }
+}
+
+async fn sentinel(State(state): State<Arc<MockState>>) -> Response {
+    state.sentinel_calls.fetch_add(1, Ordering::SeqCst);
+    if let Some(status) = state.options.sentinel_status {
+        let status = StatusCode::from_u16(status).unwrap();
+        return (status, r#"{"detail":"unsupported_country"}"#).into_response();
+    }
+    Json(json!({
+        "token": "mock-token",
+        "proofofwork": {
+            "required": true,
+            "seed": "0.42",
+            // every hash is below this difficulty, the proof is found at once
+            "difficulty": "ffffff",
+        }
+    }))
+    .into_response()
+}
+
+async fn conversation(
+    State(state): State<Arc<MockState>>,
+    headers: HeaderMap,
+    Json(body): Json<Value>,
+) -> Response {
+    let calls = state.conversation_calls.fetch_add(1, Ordering::SeqCst) + 1;
+    state.conversations.lock().unwrap().push(body);
+    state.headers.lock().unwrap().push(headers);
+
+    if let Some((status, times)) = state.options.fail_status {
+        if calls <= times {
+            let status = StatusCode::from_u16(status).unwrap();
+            return (status, r#"{"detail":"mock failure"}"#).into_response();
+        }
+    }
+
+    let body = sse_body(&state.options);
+    let chunks = match state.options.chunk_size {
+        Some(size) => body
+            .as_bytes()
+            .chunks(size)
+            .map(Bytes::copy_from_slice)
+            .collect::<Vec<_>>(),
+        None => body
+            .split_inclusive("\n\n")
+            .map(|frame| Bytes::copy_from_slice(frame.as_bytes()))
+            .collect::<Vec<_>>(),
+    };
+    let delay = state.options.chunk_delay;
+    let stream = futures::stream::iter(chunks).then(move |chunk| async move {
+        if let Some(delay) = delay {
+            tokio::time::sleep(delay).await;
+        }
+        Ok::<_, Infallible>(chunk)
+    });
+
+    Response::builder()
+        .header("content-type", "text/event-stream; charset=utf-8")
+        .body(Body::from_stream(stream))
+        .unwrap()
+}
+
+fn message_frame(text: &str, finished: bool) -> String {
+    let data = json!({
+        "message": {
+            "id": "mock-message-id",
+            "author": {"role": "assistant", "name": null, "metadata": {}},
+            "create_time": 1714000000.0,
+            "update_time": null,
+            "content": {"content_type": "text", "parts": [text]},
+            "status": if finished { "finished_successfully" } else { "in_progress" },
+            "end_turn": if finished { Some(true) } else { None },
+            "weight": 1.0,
+            "metadata": {
+                "citations": [],
+                "gizmo_id": null,
+                "message_type": "next",
+                "model_slug": MOCK_MODEL,
+                "default_model_slug": MOCK_MODEL,
+                "pad": "AAAAAAAA",
+                "parent_id": "mock-parent-id",
+                "finish_details": if finished { Some(json!({"type": "stop", "stop_tokens": [100260]})) } else { None },
+                "is_complete": finished,
+            },
+            "recipient": "all",
+        },
+        "conversation_id": "mock-conversation-id",
+        "error": null,
+    });
+    format!("data: {}\n\n", data)
+}
+
+/// The full event stream for the options: cumulative `parts`, then the
+/// finished message and `[DONE]`.
+pub fn sse_body(options: &MockOptions) -> String {
+    let mut body = String::new();
+    let mut text = String::new();
+    for (i, word) in options.reply.split_inclusive(' ').enumerate() {
+        text.push_str(word);
+        body.push_str(&message_frame(&text, false));
+        if options.heartbeats {
+            body.push_str("data: 2024-04-25 08:00:00.000000\n\n");
+        }
+        if i == 0 && options.malformed {
+            body.push_str("data: {\"message\": not json\n\n");
+        }
+    }
+    match options.error.as_ref() {
+        Some(error) => {
+            let data = json!({
+                "message": null,
+                "conversation_id": "mock-conversation-id",
+                "error": error,
+            });
+            body.push_str(&format!("data: {}\n\n", data));
+        }
+        None if options.unfinished => {}
+        None => body.push_str(&message_frame(&text, true)),
+    }
+    if !options.no_done {
+        body.push_str("data: [DONE]\n\n");
+    }
+    body
+}
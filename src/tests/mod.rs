@@ -0,0 +1,28 @@
+mod cli;
+mod json_mode;
+mod mock;
+mod proxy;
+mod sse;
+mod stream;
+mod tokens;
+mod tools;
+
+use crate::fgpt::AppStateRef;
+use clap::Parser;
+use std::sync::Arc;
+
+/// Build the state the way `main` does, against the mock upstream and with
+/// short retry delays.
+pub fn test_state(upstream: &str, args: &[&str]) -> AppStateRef {
+    let mut argv = vec![
+        "fgpt",
+        "--upstream",
+        upstream,
+        "--retry-delay",
+        "10",
+        "--retry-max-delay",
+        "20",
+    ];
+    argv.extend_from_slice(args);
+    Arc::new(crate::Args::parse_from(argv).into())
+}
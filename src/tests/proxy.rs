@@ -0,0 +1,1240 @@
+use super::{
+    mock::{MockOptions, MockUpstream},
+    test_state,
+};
+use crate::proxy;
+use serde_json::{json, Value};
+
+/// Serve the proxy on a free port, returning its base URL.
+pub async fn start_proxy(mock: &MockUpstream, args: &[&str]) -> String {
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let mut argv = vec!["-s", addr.as_str()];
+    argv.extend_from_slice(args);
+    tokio::spawn(proxy::serve(test_state(&mock.url, &argv)));
+
+    for _ in 0..200 {
+        if tokio::net::TcpStream::connect(&addr).await.is_ok() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    format!("http://{}/v1", addr)
+}
+
+async fn chat(base_url: &str, body: Value) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/chat/completions", base_url))
+        .json(&body)
+        .send()
+        .await
+        .unwrap()
+}
+
+/// The `data:` payloads of an SSE body.
+pub fn sse_data(body: &str) -> Vec<String> {
+    body.lines()
+        .filter_map(|line| line.strip_prefix("data: "))
+        .map(|data| data.to_string())
+        .collect()
+}
+
+#[tokio::test]
+async fn test_proxy_completions() {
+    let mock = MockUpstream::start(MockOptions::default()).await;
+    let base_url = start_proxy(&mock, &[]).await;
+
+    let resp = chat(
+        &base_url,
+        json!({
+            "model": "gpt-3.5-turbo",
+            "messages": [{"role": "user", "content": "hi"}],
+        }),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+    let body = resp.json::<Value>().await.unwrap();
+    assert_eq!(body["object"], "chat.completion");
+    assert!(body["created"].is_u64());
+    assert_eq!(body["model"], "gpt-3.5-turbo");
+    assert_eq!(
+        body["choices"][0]["message"]["content"],
+        MockOptions::default().reply
+    );
+    assert_eq!(body["choices"][0]["finish_reason"], "stop");
+    assert!(body["usage"]["completion_tokens"].as_i64().unwrap() > 0);
+}
+
+#[tokio::test]
+async fn test_proxy_completions_stream() {
+    let mock = MockUpstream::start(MockOptions {
+        chunk_size: Some(7),
+        ..Default::default()
+    })
+    .await;
+    let base_url = start_proxy(&mock, &[]).await;
+
+    let resp = chat(
+        &base_url,
+        json!({
+            "model": "gpt-3.5-turbo",
+            "messages": [{"role": "user", "content": "hi"}],
+            "stream": true,
+        }),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+    let body = resp.text().await.unwrap();
+    let text = sse_data(&body)
+        .iter()
+        .filter_map(|data| serde_json::from_str::<Value>(data).ok())
+        .filter_map(|chunk| {
+            chunk["choices"][0]["delta"]["content"]
+                .as_str()
+                .map(|s| s.to_string())
+        })
+        .collect::<String>();
+    assert_eq!(text, MockOptions::default().reply);
+}
+
+#[tokio::test]
+async fn test_proxy_completions_stream_sequence() {
+    let mock = MockUpstream::start(MockOptions::default()).await;
+    let base_url = start_proxy(&mock, &[]).await;
+
+    let resp = chat(
+        &base_url,
+        json!({
+            "messages": [{"role": "user", "content": "hi"}],
+            "stream": true,
+        }),
+    )
+    .await;
+    let data = sse_data(&resp.text().await.unwrap());
+    assert_eq!(data.last().map(String::as_str), Some("[DONE]"));
+
+    let chunks = data[..data.len() - 1]
+        .iter()
+        .map(|data| serde_json::from_str::<Value>(data).unwrap())
+        .collect::<Vec<_>>();
+    assert!(chunks.len() >= 3);
+    let (first, rest) = chunks.split_first().unwrap();
+    let (last, deltas) = rest.split_last().unwrap();
+
+    assert_eq!(first["choices"][0]["delta"]["role"], "assistant");
+    assert!(first["choices"][0]["finish_reason"].is_null());
+    for chunk in deltas {
+        assert_eq!(chunk["id"], first["id"]);
+        assert_eq!(chunk["object"], "chat.completion.chunk");
+        assert!(chunk["choices"][0]["delta"].get("role").is_none());
+        assert!(chunk["choices"][0]["finish_reason"].is_null());
+    }
+    assert_eq!(last["choices"][0]["delta"], json!({}));
+    assert_eq!(last["choices"][0]["finish_reason"], "stop");
+}
+
+#[tokio::test]
+async fn test_proxy_completions_stream_error() {
+    let mock = MockUpstream::start(MockOptions {
+        error: Some("Something went wrong".to_string()),
+        ..Default::default()
+    })
+    .await;
+    let base_url = start_proxy(&mock, &[]).await;
+
+    let resp = chat(
+        &base_url,
+        json!({"messages": [{"role": "user", "content": "hi"}], "stream": true}),
+    )
+    .await;
+    let data = sse_data(&resp.text().await.unwrap());
+    assert_eq!(data.last().map(String::as_str), Some("[DONE]"));
+    let chunks = data[..data.len() - 1]
+        .iter()
+        .map(|data| serde_json::from_str::<Value>(data).unwrap())
+        .collect::<Vec<_>>();
+    let (error, chunks) = chunks.split_last().unwrap();
+    assert_eq!(error["error"]["type"], "api_error");
+    assert_eq!(error["error"]["code"], "upstream_error");
+    assert!(error["error"]["message"]
+        .as_str()
+        .unwrap()
+        .contains("Something went wrong"));
+    for chunk in chunks {
+        let choice = &chunk["choices"][0];
+        assert!(choice["finish_reason"].is_null());
+        assert!(!choice["delta"]["content"]
+            .as_str()
+            .unwrap_or_default()
+            .contains("Something went wrong"));
+    }
+}
+
+#[tokio::test]
+async fn test_proxy_completions_stream_usage() {
+    let mock = MockUpstream::start(MockOptions::default()).await;
+    let base_url = start_proxy(&mock, &[]).await;
+
+    let resp = chat(
+        &base_url,
+        json!({
+            "messages": [{"role": "user", "content": "hi"}],
+            "stream": true,
+            "stream_options": {"include_usage": true},
+        }),
+    )
+    .await;
+    let data = sse_data(&resp.text().await.unwrap());
+    assert_eq!(data.last().map(String::as_str), Some("[DONE]"));
+
+    let chunks = data[..data.len() - 1]
+        .iter()
+        .map(|data| serde_json::from_str::<Value>(data).unwrap())
+        .collect::<Vec<_>>();
+    let (usage, rest) = chunks.split_last().unwrap();
+    assert!(rest.iter().all(|chunk| chunk["usage"].is_null()));
+    assert_eq!(usage["choices"], json!([]));
+    let prompt_tokens = usage["usage"]["prompt_tokens"].as_i64().unwrap();
+    let completion_tokens = usage["usage"]["completion_tokens"].as_i64().unwrap();
+    assert!(prompt_tokens > 0 && completion_tokens > 0);
+    assert_eq!(
+        usage["usage"]["total_tokens"].as_i64().unwrap(),
+        prompt_tokens + completion_tokens
+    );
+}
+
+#[tokio::test]
+async fn test_proxy_completions_stream_heartbeats() {
+    let mock = MockUpstream::start(MockOptions {
+        heartbeats: true,
+        chunk_size: Some(5),
+        ..Default::default()
+    })
+    .await;
+    let base_url = start_proxy(&mock, &[]).await;
+
+    let resp = chat(
+        &base_url,
+        json!({
+            "messages": [{"role": "user", "content": "hi"}],
+            "stream": true,
+        }),
+    );
+    let body = tokio::time::timeout(std::time::Duration::from_secs(10), async {
+        resp.await.text().await.unwrap()
+    })
+    .await
+    .expect("the stream stalled on a heartbeat");
+
+    assert!(body.lines().any(|line| line == ": ping"));
+    let data = sse_data(&body);
+    assert_eq!(data.last().map(String::as_str), Some("[DONE]"));
+    let text = data
+        .iter()
+        .filter_map(|data| serde_json::from_str::<Value>(data).ok())
+        .filter_map(|chunk| {
+            chunk["choices"][0]["delta"]["content"]
+                .as_str()
+                .map(|s| s.to_string())
+        })
+        .collect::<String>();
+    assert_eq!(text, MockOptions::default().reply);
+}
+
+#[tokio::test]
+async fn test_proxy_completions_stream_keep_alive() {
+    let mock = MockUpstream::start(MockOptions {
+        reply: "hi".to_string(),
+        chunk_delay: Some(std::time::Duration::from_millis(1200)),
+        ..Default::default()
+    })
+    .await;
+    let base_url = start_proxy(&mock, &["--sse-keep-alive", "1"]).await;
+
+    let resp = chat(
+        &base_url,
+        json!({
+            "messages": [{"role": "user", "content": "hi"}],
+            "stream": true,
+        }),
+    )
+    .await;
+    let body = resp.text().await.unwrap();
+    assert!(body.lines().any(|line| line == ": ping"));
+    assert_eq!(sse_data(&body).last().map(String::as_str), Some("[DONE]"));
+
+    // a JSON reply is replayed once complete, the pings run meanwhile
+    let mock = MockUpstream::start(MockOptions {
+        reply: "{}".to_string(),
+        chunk_delay: Some(std::time::Duration::from_millis(1200)),
+        ..Default::default()
+    })
+    .await;
+    let base_url = start_proxy(&mock, &["--sse-keep-alive", "1"]).await;
+    let resp = chat(
+        &base_url,
+        json!({
+            "messages": [{"role": "user", "content": "hi"}],
+            "response_format": {"type": "json_object"},
+            "stream": true,
+        }),
+    )
+    .await;
+    let body = resp.text().await.unwrap();
+    assert!(body.lines().any(|line| line == ": ping"));
+    let data = sse_data(&body);
+    assert_eq!(data.last().map(String::as_str), Some("[DONE]"));
+    assert!(data.iter().any(|data| data.contains(r#""content":"{}""#)));
+}
+
+async fn client_cancelled(base_url: &str) -> u64 {
+    let stats_url = base_url.trim_end_matches("/v1").to_string() + "/stats";
+    let body = reqwest::get(stats_url)
+        .await
+        .unwrap()
+        .json::<Value>()
+        .await
+        .unwrap();
+    body["client_cancelled"].as_u64().unwrap()
+}
+
+/// Wait for the proxy to notice the cancelled requests.
+async fn wait_client_cancelled(base_url: &str, expected: u64) {
+    for _ in 0..100 {
+        if client_cancelled(base_url).await == expected {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(client_cancelled(base_url).await, expected);
+}
+
+#[tokio::test]
+async fn test_proxy_client_cancelled() {
+    let mock = MockUpstream::start(MockOptions {
+        chunk_delay: Some(std::time::Duration::from_millis(200)),
+        ..Default::default()
+    })
+    .await;
+    let base_url = start_proxy(&mock, &[]).await;
+    let request = json!({
+        "messages": [{"role": "user", "content": "hi"}],
+        "stream": true,
+    });
+
+    let mut resp = chat(&base_url, request.clone()).await;
+    assert!(resp.chunk().await.unwrap().is_some());
+    drop(resp);
+    wait_client_cancelled(&base_url, 1).await;
+
+    let resp = reqwest::Client::new()
+        .post(format!("{}/chat/completions", base_url))
+        .timeout(std::time::Duration::from_millis(500))
+        .json(&json!({"messages": request["messages"]}))
+        .send()
+        .await;
+    assert!(resp.unwrap_err().is_timeout());
+    wait_client_cancelled(&base_url, 2).await;
+
+    // a completion read to its end is not counted
+    let resp = chat(&base_url, request).await;
+    assert_eq!(
+        sse_data(&resp.text().await.unwrap()).last().unwrap(),
+        "[DONE]"
+    );
+    assert_eq!(client_cancelled(&base_url).await, 2);
+}
+
+#[tokio::test]
+async fn test_proxy_completions_limits() {
+    let mock = MockUpstream::start(MockOptions::default()).await;
+    let base_url = start_proxy(&mock, &[]).await;
+
+    let resp = chat(
+        &base_url,
+        json!({
+            "messages": [{"role": "user", "content": "hi"}],
+            "max_tokens": 2,
+        }),
+    )
+    .await;
+    let body = resp.json::<Value>().await.unwrap();
+    assert_eq!(body["choices"][0]["message"]["content"], "Hello from");
+    assert_eq!(body["choices"][0]["finish_reason"], "length");
+    assert_eq!(body["usage"]["completion_tokens"], 2);
+
+    let resp = chat(
+        &base_url,
+        json!({
+            "messages": [{"role": "user", "content": "hi"}],
+            "stop": "upstream",
+            "stream": true,
+        }),
+    )
+    .await;
+    let chunks = sse_data(&resp.text().await.unwrap())
+        .iter()
+        .filter_map(|data| serde_json::from_str::<Value>(data).ok())
+        .collect::<Vec<_>>();
+    let text = chunks
+        .iter()
+        .filter_map(|chunk| chunk["choices"][0]["delta"]["content"].as_str())
+        .collect::<String>();
+    assert_eq!(text, "Hello from the mock ");
+    assert_eq!(
+        chunks.last().unwrap()["choices"][0]["finish_reason"],
+        "stop"
+    );
+
+    let resp = chat(
+        &base_url,
+        json!({"messages": [{"role": "user", "content": "hi"}], "max_tokens": 0}),
+    )
+    .await;
+    assert_eq!(resp.status(), 400);
+    let body = resp.json::<Value>().await.unwrap();
+    assert_eq!(body["error"]["param"], "max_tokens");
+}
+
+#[tokio::test]
+async fn test_proxy_completions_choices() {
+    let mock = MockUpstream::start(MockOptions {
+        chunk_size: Some(9),
+        ..Default::default()
+    })
+    .await;
+    let base_url = start_proxy(&mock, &["--max-choices", "3"]).await;
+    let reply = MockOptions::default().reply;
+
+    let resp = chat(
+        &base_url,
+        json!({"messages": [{"role": "user", "content": "hi"}], "n": 3}),
+    )
+    .await;
+    let body = resp.json::<Value>().await.unwrap();
+    let choices = body["choices"].as_array().unwrap();
+    assert_eq!(choices.len(), 3);
+    for (index, choice) in choices.iter().enumerate() {
+        assert_eq!(choice["index"], index);
+        assert_eq!(choice["message"]["content"], reply);
+    }
+    assert_eq!(mock.conversation_calls(), 3);
+    let completion_tokens = body["usage"]["completion_tokens"].as_i64().unwrap();
+    assert_eq!(completion_tokens % 3, 0);
+
+    let resp = chat(
+        &base_url,
+        json!({
+            "messages": [{"role": "user", "content": "hi"}],
+            "n": 2,
+            "stream": true,
+            "stream_options": {"include_usage": true},
+        }),
+    )
+    .await;
+    let data = sse_data(&resp.text().await.unwrap());
+    assert_eq!(data.last().map(String::as_str), Some("[DONE]"));
+    let chunks = data
+        .iter()
+        .filter_map(|data| serde_json::from_str::<Value>(data).ok())
+        .collect::<Vec<_>>();
+    for index in 0..2 {
+        let choice_chunks = chunks
+            .iter()
+            .filter_map(|chunk| chunk["choices"].as_array()?.first())
+            .filter(|choice| choice["index"] == index)
+            .collect::<Vec<_>>();
+        let text = choice_chunks
+            .iter()
+            .filter_map(|choice| choice["delta"]["content"].as_str())
+            .collect::<String>();
+        assert_eq!(text, reply);
+        assert_eq!(choice_chunks[0]["delta"]["role"], "assistant");
+        assert_eq!(choice_chunks.last().unwrap()["finish_reason"], "stop");
+    }
+    let usage = &chunks.last().unwrap()["usage"];
+    assert_eq!(usage["completion_tokens"], completion_tokens / 3 * 2);
+
+    let resp = chat(
+        &base_url,
+        json!({"messages": [{"role": "user", "content": "hi"}], "n": 4}),
+    )
+    .await;
+    assert_eq!(resp.status(), 400);
+    let body = resp.json::<Value>().await.unwrap();
+    assert_eq!(body["error"]["param"], "n");
+}
+
+const TOOL_CALL_REPLY: &str =
+    r#"{"tool_calls": [{"name": "get_weather", "arguments": {"city": "Paris"}}]}"#;
+
+fn tool_request(stream: bool) -> Value {
+    json!({
+        "messages": [
+            {"role": "user", "content": "Weather in Paris?"},
+            {"role": "assistant", "content": null, "tool_calls": [{
+                "id": "call_1",
+                "type": "function",
+                "function": {"name": "get_weather", "arguments": "{\"city\":\"Oslo\"}"}
+            }]},
+            {"role": "tool", "tool_call_id": "call_1", "content": "sunny"},
+        ],
+        "tools": [{
+            "type": "function",
+            "function": {
+                "name": "get_weather",
+                "parameters": {"type": "object", "properties": {"city": {"type": "string"}}}
+            }
+        }],
+        "stream": stream,
+    })
+}
+
+#[tokio::test]
+async fn test_proxy_tool_calls() {
+    let mock = MockUpstream::start(MockOptions {
+        reply: TOOL_CALL_REPLY.to_string(),
+        ..Default::default()
+    })
+    .await;
+    let base_url = start_proxy(&mock, &[]).await;
+
+    let resp = chat(&base_url, tool_request(false)).await;
+    assert_eq!(resp.status(), 200);
+    let body = resp.json::<Value>().await.unwrap();
+    let choice = &body["choices"][0];
+    assert_eq!(choice["finish_reason"], "tool_calls");
+    assert!(choice["message"]["content"].is_null());
+    let call = &choice["message"]["tool_calls"][0];
+    assert_eq!(call["type"], "function");
+    assert_eq!(call["function"]["name"], "get_weather");
+    assert_eq!(call["function"]["arguments"], r#"{"city":"Paris"}"#);
+
+    let messages = mock.last_conversation().unwrap()["messages"].clone();
+    let roles = messages
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|message| message["author"]["role"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(roles, vec!["system", "user", "assistant", "user"]);
+    let part = |i: usize| {
+        messages[i]["content"]["parts"][0]
+            .as_str()
+            .unwrap()
+            .to_string()
+    };
+    assert!(part(0).contains("get_weather"));
+    assert!(part(2).contains("Oslo"));
+    assert!(part(3).contains("call_1") && part(3).contains("sunny"));
+
+    let resp = chat(&base_url, tool_request(true)).await;
+    let chunks = sse_data(&resp.text().await.unwrap())
+        .iter()
+        .filter_map(|data| serde_json::from_str::<Value>(data).ok())
+        .collect::<Vec<_>>();
+    let deltas = chunks
+        .iter()
+        .map(|chunk| &chunk["choices"][0])
+        .collect::<Vec<_>>();
+    assert!(deltas.iter().all(|choice| choice["delta"]["content"]
+        .as_str()
+        .unwrap_or_default()
+        .is_empty()));
+    let calls = deltas
+        .iter()
+        .filter_map(|choice| choice["delta"]["tool_calls"].as_array())
+        .flatten()
+        .collect::<Vec<_>>();
+    assert_eq!(calls.len(), 1);
+    assert_eq!(calls[0]["index"], 0);
+    assert_eq!(calls[0]["function"]["name"], "get_weather");
+    assert_eq!(deltas.last().unwrap()["finish_reason"], "tool_calls");
+}
+
+#[tokio::test]
+async fn test_proxy_tools_plain_reply() {
+    let mock = MockUpstream::start(MockOptions::default()).await;
+    let base_url = start_proxy(&mock, &[]).await;
+
+    let resp = chat(&base_url, tool_request(true)).await;
+    let chunks = sse_data(&resp.text().await.unwrap())
+        .iter()
+        .filter_map(|data| serde_json::from_str::<Value>(data).ok())
+        .collect::<Vec<_>>();
+    let text = chunks
+        .iter()
+        .filter_map(|chunk| chunk["choices"][0]["delta"]["content"].as_str())
+        .collect::<String>();
+    assert_eq!(text, MockOptions::default().reply);
+    assert_eq!(
+        chunks.last().unwrap()["choices"][0]["finish_reason"],
+        "stop"
+    );
+
+    let mut request = tool_request(false);
+    request["tool_choice"] = json!({"type": "function", "function": {"name": "unknown"}});
+    let resp = chat(&base_url, request).await;
+    assert_eq!(resp.status(), 400);
+    let body = resp.json::<Value>().await.unwrap();
+    assert_eq!(body["error"]["param"], "tool_choice");
+}
+
+#[tokio::test]
+async fn test_proxy_response_format() {
+    let mock = MockUpstream::start(MockOptions {
+        reply: "```json\n{\"city\": \"Paris\"}\n```".to_string(),
+        ..Default::default()
+    })
+    .await;
+    let base_url = start_proxy(&mock, &["--json-retries", "1", "--conversation-ttl", "60"]).await;
+    let request = |schema: Value, stream: bool| {
+        json!({
+            "messages": [{"role": "user", "content": "Where?"}],
+            "response_format": {
+                "type": "json_schema",
+                "json_schema": {"name": "place", "schema": schema},
+            },
+            "stream": stream,
+        })
+    };
+    let schema = json!({"type": "object", "required": ["city"]});
+
+    let resp = chat(&base_url, request(schema.clone(), false)).await;
+    assert_eq!(resp.status(), 200);
+    let body = resp.json::<Value>().await.unwrap();
+    assert_eq!(
+        body["choices"][0]["message"]["content"],
+        r#"{"city": "Paris"}"#
+    );
+    let system = &mock.last_conversation().unwrap()["messages"][0];
+    assert_eq!(system["author"]["role"], "system");
+    assert!(system["content"]["parts"][0]
+        .as_str()
+        .unwrap()
+        .contains("\"required\""));
+    assert_eq!(mock.conversation_calls(), 1);
+
+    let resp = chat(&base_url, request(schema, true)).await;
+    let data = sse_data(&resp.text().await.unwrap());
+    assert_eq!(data.last().map(String::as_str), Some("[DONE]"));
+    let text = data
+        .iter()
+        .filter_map(|data| serde_json::from_str::<Value>(data).ok())
+        .filter_map(|chunk| {
+            chunk["choices"][0]["delta"]["content"]
+                .as_str()
+                .map(|s| s.to_string())
+        })
+        .collect::<String>();
+    assert_eq!(text, r#"{"city": "Paris"}"#);
+    assert_eq!(mock.conversation_calls(), 2);
+
+    // the mock always answers the same, so the retry fails too
+    let resp = chat(
+        &base_url,
+        request(json!({"type": "object", "required": ["country"]}), false),
+    )
+    .await;
+    assert_eq!(resp.status(), 502);
+    let body = resp.json::<Value>().await.unwrap();
+    assert_eq!(body["error"]["code"], "invalid_response_format");
+    assert_eq!(mock.conversation_calls(), 4);
+    let retry = mock.last_conversation().unwrap()["messages"].clone();
+    let last = retry.as_array().unwrap().last().unwrap();
+    assert!(last["content"]["parts"][0]
+        .as_str()
+        .unwrap()
+        .contains("missing property country"));
+
+    // the fences were stripped, so the reply can't be continued upstream
+    let mut next = request(json!({"type": "object", "required": ["city"]}), false);
+    next["messages"] = json!([
+        {"role": "user", "content": "Where?"},
+        {"role": "assistant", "content": r#"{"city": "Paris"}"#},
+        {"role": "user", "content": "Why?"},
+    ]);
+    let resp = chat(&base_url, next).await;
+    assert_eq!(resp.status(), 200);
+    let sent = mock.last_conversation().unwrap();
+    assert_eq!(sent["conversation_id"], Value::Null);
+}
+
+#[tokio::test]
+async fn test_proxy_conversation_continuity() {
+    let mock = MockUpstream::start(MockOptions::default()).await;
+    let base_url = start_proxy(&mock, &["--conversation-ttl", "60"]).await;
+    let reply = MockOptions::default().reply;
+    let sent = |mock: &MockUpstream| {
+        let body = mock.last_conversation().unwrap();
+        let parts = body["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|message| message["content"]["parts"][0].as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        (
+            body["conversation_id"].as_str().map(|id| id.to_string()),
+            parts,
+        )
+    };
+
+    // the first turn is streamed, the reply is remembered at its end
+    let resp = chat(
+        &base_url,
+        json!({"messages": [{"role": "user", "content": "hi"}], "stream": true}),
+    )
+    .await;
+    resp.text().await.unwrap();
+    assert_eq!(sent(&mock), (None, vec!["hi".to_string()]));
+
+    let resp = chat(
+        &base_url,
+        json!({"messages": [
+            {"role": "user", "content": "hi"},
+            {"role": "assistant", "content": reply},
+            {"role": "user", "content": "more"},
+        ]}),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(
+        sent(&mock),
+        (
+            Some("mock-conversation-id".to_string()),
+            vec!["more".to_string()]
+        )
+    );
+    assert_eq!(
+        mock.last_conversation().unwrap()["parent_message_id"],
+        "mock-message-id"
+    );
+
+    // a reply cut by the limits can't be continued upstream
+    for limit in [json!({"max_tokens": 2}), json!({"stop": "mock"})] {
+        let mut request = json!({"messages": [{"role": "user", "content": "cut"}]});
+        request
+            .as_object_mut()
+            .unwrap()
+            .extend(limit.as_object().unwrap().clone());
+        let body = chat(&base_url, request)
+            .await
+            .json::<Value>()
+            .await
+            .unwrap();
+        let text = body["choices"][0]["message"]["content"].clone();
+        assert_ne!(text, json!(reply));
+        chat(
+            &base_url,
+            json!({"messages": [
+                {"role": "user", "content": "cut"},
+                {"role": "assistant", "content": text},
+                {"role": "user", "content": "more"},
+            ]}),
+        )
+        .await;
+        assert_eq!(sent(&mock).0, None);
+        assert_eq!(sent(&mock).1.len(), 3);
+    }
+
+    // an edited history starts over
+    let resp = chat(
+        &base_url,
+        json!({"messages": [
+            {"role": "user", "content": "hi"},
+            {"role": "assistant", "content": "edited"},
+            {"role": "user", "content": "more"},
+        ]}),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(sent(&mock).0, None);
+    assert_eq!(sent(&mock).1.len(), 3);
+
+    // the client names the conversation
+    let client = reqwest::Client::new();
+    let chat_with_id = |messages: Value| {
+        client
+            .post(format!("{}/chat/completions", base_url))
+            .header("X-Conversation-Id", "client-1")
+            .json(&json!({ "messages": messages }))
+            .send()
+    };
+    chat_with_id(json!([{"role": "user", "content": "hello"}]))
+        .await
+        .unwrap();
+    assert_eq!(sent(&mock).0, None);
+    chat_with_id(json!([
+        {"role": "user", "content": "hello"},
+        {"role": "assistant", "content": reply},
+        {"role": "user", "content": "again"},
+    ]))
+    .await
+    .unwrap();
+    assert_eq!(
+        sent(&mock),
+        (
+            Some("mock-conversation-id".to_string()),
+            vec!["again".to_string()]
+        )
+    );
+    // regenerating the last reply, or editing one, starts over
+    for previous in [reply.as_str(), "edited"] {
+        chat_with_id(json!([
+            {"role": "user", "content": "hello"},
+            {"role": "assistant", "content": previous},
+            {"role": "user", "content": "again"},
+        ]))
+        .await
+        .unwrap();
+        assert_eq!(sent(&mock).0, None);
+        assert_eq!(sent(&mock).1.len(), 3);
+    }
+
+    // the client sends a tool call reply back as `tool_calls`
+    let mock = MockUpstream::start(MockOptions {
+        reply: TOOL_CALL_REPLY.to_string(),
+        ..Default::default()
+    })
+    .await;
+    let base_url = start_proxy(&mock, &["--conversation-ttl", "60"]).await;
+    for stream in [false, true] {
+        let mut request = tool_request(stream);
+        request["messages"] = json!([{"role": "user", "content": "Weather in Paris?"}]);
+        let resp = chat(&base_url, request.clone()).await;
+        let tool_calls = match stream {
+            false => {
+                resp.json::<Value>().await.unwrap()["choices"][0]["message"]["tool_calls"].clone()
+            }
+            true => sse_data(&resp.text().await.unwrap())
+                .iter()
+                .filter_map(|data| serde_json::from_str::<Value>(data).ok())
+                .filter_map(|chunk| {
+                    let mut call = chunk["choices"][0]["delta"]["tool_calls"][0].clone();
+                    call.as_object_mut()?.remove("index");
+                    Some(call)
+                })
+                .collect(),
+        };
+        assert_eq!(tool_calls.as_array().unwrap().len(), 1);
+        let call_id = tool_calls[0]["id"].clone();
+
+        request["messages"] = json!([
+            {"role": "user", "content": "Weather in Paris?"},
+            {"role": "assistant", "content": null, "tool_calls": tool_calls},
+            {"role": "tool", "tool_call_id": call_id, "content": "sunny"},
+        ]);
+        chat(&base_url, request).await.text().await.unwrap();
+        let (conversation_id, parts) = sent(&mock);
+        assert_eq!(conversation_id.as_deref(), Some("mock-conversation-id"));
+        assert_eq!(parts.len(), 1);
+        assert!(parts[0].contains("sunny"));
+    }
+}
+
+#[tokio::test]
+async fn test_proxy_message_shapes() {
+    let mock = MockUpstream::start(MockOptions::default()).await;
+    let base_url = start_proxy(&mock, &[]).await;
+
+    let resp = chat(
+        &base_url,
+        json!({"messages": [
+            {"role": "developer", "content": [{"type": "text", "text": "Be brief."}]},
+            {"role": "user", "name": "alice", "content": [
+                {"type": "text", "text": "Hello"},
+                {"type": "text", "text": "there"},
+            ]},
+            {"role": "assistant", "content": null, "refusal": null},
+            {"role": "assistant", "content": [{"type": "refusal", "refusal": "No."}]},
+            {"role": "user", "content": "ok"},
+        ]}),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+    let messages = mock.last_conversation().unwrap()["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|message| {
+            (
+                message["author"]["role"].as_str().unwrap().to_string(),
+                message["content"]["parts"][0].as_str().unwrap().to_string(),
+            )
+        })
+        .collect::<Vec<_>>();
+    let expected = [
+        ("system", "Be brief."),
+        ("user", "Hello\nthere"),
+        ("assistant", ""),
+        ("assistant", "No."),
+        ("user", "ok"),
+    ];
+    assert_eq!(
+        messages,
+        expected.map(|(role, text)| (role.to_string(), text.to_string()))
+    );
+
+    let resp = chat(
+        &base_url,
+        json!({"messages": [{"role": "user", "content": [
+            {"type": "text", "text": "What is this?"},
+            {"type": "image_url", "image_url": {"url": "https://example.com/a.png"}},
+        ]}]}),
+    )
+    .await;
+    assert_eq!(resp.status(), 400);
+    let body = resp.json::<Value>().await.unwrap();
+    assert_eq!(body["error"]["param"], "messages[0].content[1].type");
+    assert!(body["error"]["message"]
+        .as_str()
+        .unwrap()
+        .contains("image_url"));
+
+    let resp = chat(
+        &base_url,
+        json!({"messages": [{"role": "narrator", "content": "hi"}]}),
+    )
+    .await;
+    assert_eq!(resp.status(), 400);
+    let body = resp.json::<Value>().await.unwrap();
+    assert_eq!(body["error"]["param"], "messages[0].role");
+}
+
+#[tokio::test]
+async fn test_proxy_legacy_completions() {
+    let mock = MockUpstream::start(MockOptions::default()).await;
+    let base_url = start_proxy(&mock, &[]).await;
+    let reply = MockOptions::default().reply;
+    let complete = |body: Value| {
+        reqwest::Client::new()
+            .post(format!("{}/completions", base_url))
+            .json(&body)
+            .send()
+    };
+
+    let resp = complete(json!({"prompt": "Once upon", "echo": true}))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let body = resp.json::<Value>().await.unwrap();
+    assert_eq!(body["object"], "text_completion");
+    assert!(body["id"].as_str().unwrap().starts_with("cmpl-"));
+    assert_eq!(body["choices"][0]["text"], format!("Once upon{}", reply));
+    assert_eq!(body["choices"][0]["finish_reason"], "stop");
+    assert!(body["choices"][0]["logprobs"].is_null());
+    assert!(body["usage"]["total_tokens"].as_i64().unwrap() > 0);
+
+    let resp = complete(json!({"prompt": "fn add(a: i32, b: i32) -> i32 {", "suffix": "}"}))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let prompt = mock.last_conversation().unwrap()["messages"][0]["content"]["parts"][0]
+        .as_str()
+        .unwrap()
+        .to_string();
+    assert!(prompt.contains("<prefix>fn add(a: i32, b: i32) -> i32 {</prefix>"));
+    assert!(prompt.contains("<suffix>}</suffix>"));
+
+    let resp = complete(json!({"prompt": "Once upon", "stream": true}))
+        .await
+        .unwrap();
+    let data = sse_data(&resp.text().await.unwrap());
+    assert_eq!(data.last().map(String::as_str), Some("[DONE]"));
+    let chunks = data[..data.len() - 1]
+        .iter()
+        .map(|data| serde_json::from_str::<Value>(data).unwrap())
+        .collect::<Vec<_>>();
+    for chunk in chunks.iter() {
+        assert_eq!(chunk["object"], "text_completion");
+        assert!(chunk["choices"][0].get("delta").is_none());
+    }
+    let text = chunks
+        .iter()
+        .filter_map(|chunk| chunk["choices"][0]["text"].as_str())
+        .collect::<String>();
+    assert_eq!(text, reply);
+    assert_eq!(
+        chunks.last().unwrap()["choices"][0]["finish_reason"],
+        "stop"
+    );
+
+    let resp = complete(json!({"prompt": ["a", "b", "c"], "n": 2}))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 400);
+    let body = resp.json::<Value>().await.unwrap();
+    assert_eq!(body["error"]["param"], "n");
+}
+
+#[tokio::test]
+async fn test_proxy_legacy_completions_choices_overflow() {
+    let mock = MockUpstream::start(MockOptions::default()).await;
+    let base_url = start_proxy(&mock, &[]).await;
+
+    // n times the prompts wraps to 0 without an overflow check
+    let resp = reqwest::Client::new()
+        .post(format!("{}/completions", base_url))
+        .body(r#"{"prompt": ["a", "b"], "n": 9223372036854775808}"#)
+        .header("content-type", "application/json")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 400);
+    let body = resp.json::<Value>().await.unwrap();
+    assert_eq!(body["error"]["param"], "n");
+    assert_eq!(mock.conversation_calls(), 0);
+}
+
+#[tokio::test]
+async fn test_proxy_anthropic_messages() {
+    let mock = MockUpstream::start(MockOptions::default()).await;
+    let base_url = start_proxy(&mock, &["--anthropic"]).await;
+    let reply = MockOptions::default().reply;
+    let messages = |body: Value| {
+        reqwest::Client::new()
+            .post(format!("{}/messages", base_url))
+            .json(&body)
+            .send()
+    };
+
+    let resp = messages(json!({
+        "model": "gpt-3.5-turbo",
+        "max_tokens": 1024,
+        "system": "Be brief.",
+        "messages": [{"role": "user", "content": [{"type": "text", "text": "hi"}]}],
+    }))
+    .await
+    .unwrap();
+    assert_eq!(resp.status(), 200);
+    let body = resp.json::<Value>().await.unwrap();
+    assert_eq!(body["type"], "message");
+    assert_eq!(body["role"], "assistant");
+    assert!(body["id"].as_str().unwrap().starts_with("msg_"));
+    assert_eq!(body["content"], json!([{"type": "text", "text": reply}]));
+    assert_eq!(body["stop_reason"], "end_turn");
+    assert!(body["usage"]["output_tokens"].as_i64().unwrap() > 0);
+    let upstream = mock.last_conversation().unwrap()["messages"].clone();
+    assert_eq!(upstream[0]["author"]["role"], "system");
+    assert_eq!(upstream[0]["content"]["parts"][0], "Be brief.");
+    assert_eq!(upstream[1]["content"]["parts"][0], "hi");
+
+    let resp = messages(json!({
+        "max_tokens": 1024,
+        "stop_sequences": ["upstream"],
+        "stream": true,
+        "messages": [{"role": "user", "content": "hi"}],
+    }))
+    .await
+    .unwrap();
+    let body = resp.text().await.unwrap();
+    let names = body
+        .lines()
+        .filter_map(|line| line.strip_prefix("event: "))
+        .collect::<Vec<_>>();
+    assert_eq!(names[..2], ["message_start", "content_block_start"]);
+    assert_eq!(
+        names[names.len() - 3..],
+        ["content_block_stop", "message_delta", "message_stop"]
+    );
+    let events = sse_data(&body)
+        .iter()
+        .map(|data| serde_json::from_str::<Value>(data).unwrap())
+        .collect::<Vec<_>>();
+    let text = events
+        .iter()
+        .filter(|event| event["type"] == "content_block_delta")
+        .filter_map(|event| event["delta"]["text"].as_str())
+        .collect::<String>();
+    assert_eq!(text, "Hello from the mock ");
+    let delta = events.iter().find(|event| event["type"] == "message_delta");
+    assert_eq!(delta.unwrap()["delta"]["stop_reason"], "stop_sequence");
+    assert_eq!(delta.unwrap()["delta"]["stop_sequence"], "upstream");
+
+    let resp = messages(json!({
+        "max_tokens": 1024,
+        "messages": [{"role": "user", "content": [{"type": "image", "source": {}}]}],
+    }))
+    .await
+    .unwrap();
+    assert_eq!(resp.status(), 400);
+    let body = resp.json::<Value>().await.unwrap();
+    assert_eq!(body["type"], "error");
+    assert_eq!(body["error"]["type"], "invalid_request_error");
+
+    let resp = messages(json!({"model": "unknown", "messages": []}))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 404);
+    let body = resp.json::<Value>().await.unwrap();
+    assert_eq!(body["error"]["type"], "not_found_error");
+
+    // the stream ends at an error event
+    let error_mock = MockUpstream::start(MockOptions {
+        error: Some("Something went wrong".to_string()),
+        ..Default::default()
+    })
+    .await;
+    let error_url = start_proxy(&error_mock, &["--anthropic"]).await;
+    let body = reqwest::Client::new()
+        .post(format!("{}/messages", error_url))
+        .json(&json!({"stream": true, "messages": [{"role": "user", "content": "hi"}]}))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    let names = body
+        .lines()
+        .filter_map(|line| line.strip_prefix("event: "))
+        .collect::<Vec<_>>();
+    assert_eq!(names.last(), Some(&"error"));
+    assert!(!names.contains(&"message_stop"));
+
+    let base_url = start_proxy(&mock, &[]).await;
+    let resp = reqwest::Client::new()
+        .post(format!("{}/messages", base_url))
+        .json(&json!({"messages": []}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 404);
+}
+
+#[tokio::test]
+async fn test_proxy_models() {
+    let mock = MockUpstream::start(MockOptions::default()).await;
+    let base_url = start_proxy(&mock, &["--model-alias", "gpt-4o-mini=gpt-4o-mini"]).await;
+
+    let body = reqwest::get(format!("{}/models", base_url))
+        .await
+        .unwrap()
+        .json::<Value>()
+        .await
+        .unwrap();
+    let ids = body["data"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|model| model["id"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        ids,
+        vec![
+            "gpt-3.5-turbo",
+            "gpt-4o-mini",
+            "text-davinci-002-render-sha"
+        ]
+    );
+
+    let resp = chat(
+        &base_url,
+        json!({"model": "gpt-4o-mini", "messages": [{"role": "user", "content": "hi"}]}),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(mock.last_conversation().unwrap()["model"], "gpt-4o-mini");
+
+    // the default alias is kept next to the ones passed
+    let resp = chat(
+        &base_url,
+        json!({"model": "gpt-3.5-turbo", "messages": [{"role": "user", "content": "hi"}]}),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(
+        mock.last_conversation().unwrap()["model"],
+        "text-davinci-002-render-sha"
+    );
+
+    let resp = chat(
+        &base_url,
+        json!({"model": "unknown", "messages": [{"role": "user", "content": "hi"}]}),
+    )
+    .await;
+    assert_eq!(resp.status(), 404);
+    let body = resp.json::<Value>().await.unwrap();
+    assert_eq!(body["error"]["code"], "model_not_found");
+}
+
+#[tokio::test]
+async fn test_proxy_errors() {
+    let mock = MockUpstream::start(MockOptions {
+        fail_status: Some((429, usize::MAX)),
+        ..Default::default()
+    })
+    .await;
+    let base_url = start_proxy(&mock, &["--retry-attempts", "1"]).await;
+
+    let resp = chat(
+        &base_url,
+        json!({"messages": [{"role": "user", "content": "hi"}]}),
+    )
+    .await;
+    assert_eq!(resp.status(), 429);
+    let body = resp.json::<Value>().await.unwrap();
+    assert_eq!(body["error"]["code"], "rate_limit_exceeded");
+
+    let resp = chat(&base_url, json!({"messages": "hi"})).await;
+    assert_eq!(resp.status(), 400);
+    let body = resp.json::<Value>().await.unwrap();
+    assert_eq!(body["error"]["type"], "invalid_request_error");
+
+    // 5xx after the retries, and the statuses that are never retried
+    for status in [500, 401, 403] {
+        let mock = MockUpstream::start(MockOptions {
+            fail_status: Some((status, usize::MAX)),
+            ..Default::default()
+        })
+        .await;
+        let base_url = start_proxy(&mock, &["--retry-attempts", "2"]).await;
+        let resp = chat(
+            &base_url,
+            json!({"messages": [{"role": "user", "content": "hi"}]}),
+        )
+        .await;
+        assert_eq!(resp.status(), 502);
+        let body = resp.json::<Value>().await.unwrap();
+        assert_eq!(body["error"]["type"], "api_error");
+        assert_eq!(body["error"]["code"], "upstream_error");
+        assert!(body["error"]["message"]
+            .as_str()
+            .unwrap()
+            .contains(&status.to_string()));
+    }
+}
+
+#[tokio::test]
+async fn test_proxy_timeout() {
+    let mock = MockUpstream::start(MockOptions {
+        chunk_delay: Some(std::time::Duration::from_millis(1500)),
+        ..Default::default()
+    })
+    .await;
+    let base_url = start_proxy(&mock, &["--read-timeout", "1"]).await;
+
+    let resp = chat(
+        &base_url,
+        json!({"messages": [{"role": "user", "content": "hi"}]}),
+    )
+    .await;
+    assert_eq!(resp.status(), 504);
+    let body = resp.json::<Value>().await.unwrap();
+    assert_eq!(body["error"]["code"], "timeout");
+
+    // a stream has already started, the error is its last event
+    let resp = chat(
+        &base_url,
+        json!({"messages": [{"role": "user", "content": "hi"}], "stream": true}),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+    let data = sse_data(&resp.text().await.unwrap());
+    assert_eq!(data.last().map(String::as_str), Some("[DONE]"));
+    let error = serde_json::from_str::<Value>(&data[data.len() - 2]).unwrap();
+    assert_eq!(error["error"]["code"], "timeout");
+}
@@ -0,0 +1,82 @@
+use crate::{
+    fgpt,
+    sse::{SseDecoder, SseFrame},
+};
+
+fn decode(chunks: &[&[u8]]) -> Vec<Result<SseFrame, fgpt::Error>> {
+    let mut decoder = SseDecoder::new();
+    let mut frames = vec![];
+    for chunk in chunks {
+        decoder.push(chunk);
+        while let Some(frame) = decoder.next_frame() {
+            frames.push(frame);
+        }
+    }
+    decoder.finish();
+    while let Some(frame) = decoder.next_frame() {
+        frames.push(frame);
+    }
+    frames
+}
+
+fn data(frames: &[Result<SseFrame, fgpt::Error>]) -> Vec<&str> {
+    frames
+        .iter()
+        .map(|frame| frame.as_ref().unwrap().data.as_str())
+        .collect()
+}
+
+#[test]
+fn test_sse_several_frames_in_one_chunk() {
+    let frames = decode(&[b"data: a\n\ndata: b\n\ndata: c\n\n"]);
+    assert_eq!(data(&frames), vec!["a", "b", "c"]);
+}
+
+#[test]
+fn test_sse_line_endings() {
+    let frames = decode(&[b"data: a\r\n\r\ndata: b\r\rdata: c\n\n"]);
+    assert_eq!(data(&frames), vec!["a", "b", "c"]);
+
+    // the CRLF is split between two chunks
+    let frames = decode(&[b"data: a\r", b"\n\r", b"\ndata: b\r\n\r\n"]);
+    assert_eq!(data(&frames), vec!["a", "b"]);
+}
+
+#[test]
+fn test_sse_fields() {
+    let frames = decode(&[
+        b": keep-alive\n\nevent: delta\nid: 42\nretry: 1000\ndata: line 1\ndata:line 2\n\n",
+    ]);
+    assert_eq!(frames.len(), 1);
+    assert_eq!(
+        frames[0].as_ref().unwrap(),
+        &SseFrame {
+            event: Some("delta".to_string()),
+            id: Some("42".to_string()),
+            retry: Some(1000),
+            data: "line 1\nline 2".to_string(),
+        }
+    );
+}
+
+#[test]
+fn test_sse_split_utf8() {
+    let text = "data: 你好\n\n".as_bytes();
+    let chunks = text.chunks(1).collect::<Vec<_>>();
+    let frames = decode(&chunks);
+    assert_eq!(data(&frames), vec!["你好"]);
+}
+
+#[test]
+fn test_sse_trailing_frame_at_eof() {
+    let frames = decode(&[b"data: a\n\ndata: [DONE]"]);
+    assert_eq!(data(&frames), vec!["a", "[DONE]"]);
+}
+
+#[test]
+fn test_sse_invalid_utf8() {
+    let frames = decode(&[b"data: \xff\xfe\ndata: rest\n\ndata: ok\n\n"]);
+    assert_eq!(frames.len(), 2);
+    assert!(matches!(frames[0], Err(fgpt::Error::Stream(_))));
+    assert_eq!(frames[1].as_ref().unwrap().data, "ok");
+}
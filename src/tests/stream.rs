@@ -0,0 +1,317 @@
+use super::{
+    mock::{MockOptions, MockUpstream, MOCK_MODEL},
+    test_state,
+};
+use crate::fgpt::{self, AppStateRef, CompletionEvent, CompletionRequest, Message};
+use futures::StreamExt;
+use std::time::Duration;
+
+fn user_message(content: &str) -> Message {
+    Message {
+        role: "user".to_string(),
+        content: content.to_string(),
+        content_type: Some("text".to_string()),
+    }
+}
+
+async fn open(state: AppStateRef) -> Result<fgpt::CompletionStream, fgpt::Error> {
+    let req = CompletionRequest::new(state.clone(), None, vec![user_message("hi")], None, None);
+    req.stream(state).await
+}
+
+/// Drain the stream, returning the concatenated deltas and the events seen.
+async fn collect(stream: &mut fgpt::CompletionStream) -> (String, Vec<CompletionEvent>) {
+    let mut text = String::new();
+    let mut events = vec![];
+    while let Some(event) = stream.next().await {
+        let event = event.expect("stream error");
+        if let CompletionEvent::Data(data) = &event {
+            text.push_str(data.delta_chars.as_deref().unwrap_or_default());
+        }
+        let done = matches!(event, CompletionEvent::Done);
+        events.push(event);
+        if done {
+            break;
+        }
+    }
+    (text, events)
+}
+
+#[tokio::test]
+async fn test_stream_cumulative_parts() {
+    let mock = MockUpstream::start(MockOptions::default()).await;
+    let mut stream = open(test_state(&mock.url, &[])).await.unwrap();
+    let (text, events) = collect(&mut stream).await;
+
+    assert_eq!(text, MockOptions::default().reply);
+    assert!(matches!(events.last(), Some(CompletionEvent::Done)));
+    let summary = stream.into_summary();
+    assert_eq!(summary.text, text);
+    assert_eq!(summary.finish_reason.as_deref(), Some("stop"));
+    assert_eq!(
+        summary.conversation_id.as_deref(),
+        Some("mock-conversation-id")
+    );
+    assert_eq!(summary.model_slug.as_deref(), Some(MOCK_MODEL));
+    assert!(summary.completion_tokens > 0);
+    assert_eq!(mock.sentinel_calls(), 1);
+    assert_eq!(mock.conversation_calls(), 1);
+}
+
+#[tokio::test]
+async fn test_stream_is_send_sync() {
+    fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+    let mock = MockUpstream::start(MockOptions::default()).await;
+    let stream = open(test_state(&mock.url, &[])).await.unwrap();
+    assert_send_sync(&stream);
+
+    let stream = std::sync::Arc::new(tokio::sync::Mutex::new(stream));
+    let reader = stream.clone();
+    tokio::spawn(async move { while reader.lock().await.next().await.is_some() {} })
+        .await
+        .unwrap();
+    let summary = stream.lock().await.summary().clone();
+    assert_eq!(summary.text, MockOptions::default().reply);
+    assert_eq!(summary.finish_reason.as_deref(), Some("stop"));
+}
+
+#[tokio::test]
+async fn test_stream_max_tokens() {
+    let mock = MockUpstream::start(MockOptions::default()).await;
+    let mut stream = open(test_state(&mock.url, &[])).await.unwrap();
+    stream.set_limits(fgpt::CompletionLimits {
+        max_tokens: Some(3),
+        ..Default::default()
+    });
+    let (text, events) = collect(&mut stream).await;
+    assert!(matches!(events.last(), Some(CompletionEvent::Done)));
+    assert!(stream.next().await.is_none());
+
+    let summary = stream.into_summary();
+    assert_eq!(text, "Hello from the");
+    assert_eq!(summary.text, text);
+    assert_eq!(summary.completion_tokens, 3);
+    assert_eq!(summary.finish_reason.as_deref(), Some("length"));
+}
+
+#[tokio::test]
+async fn test_stream_stop_sequences() {
+    let mock = MockUpstream::start(MockOptions::default()).await;
+    let mut stream = open(test_state(&mock.url, &[])).await.unwrap();
+    // spans two deltas, the first word must not leak
+    stream.set_limits(fgpt::CompletionLimits {
+        stop: vec!["mock up".to_string(), "never".to_string()],
+        ..Default::default()
+    });
+    let (text, events) = collect(&mut stream).await;
+    let deltas = events
+        .iter()
+        .filter_map(|event| match event {
+            CompletionEvent::Data(data) => data.delta_chars.clone(),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert!(deltas.iter().all(|delta| !delta.contains("mock")));
+
+    let summary = stream.into_summary();
+    assert_eq!(text, "Hello from the ");
+    assert_eq!(summary.text, text);
+    assert_eq!(summary.finish_reason.as_deref(), Some("stop"));
+}
+
+#[tokio::test]
+async fn test_stream_stop_prefix_at_end() {
+    let reply = MockOptions::default().reply;
+    // the reply ends with the first character of the stop sequence, held
+    // back until the stream ends without the finish details
+    for options in [
+        MockOptions {
+            unfinished: true,
+            ..Default::default()
+        },
+        MockOptions {
+            unfinished: true,
+            no_done: true,
+            ..Default::default()
+        },
+    ] {
+        let no_done = options.no_done;
+        let mock = MockUpstream::start(options).await;
+        let mut stream = open(test_state(&mock.url, &[])).await.unwrap();
+        stream.set_limits(fgpt::CompletionLimits {
+            stop: vec!["?!".to_string()],
+            ..Default::default()
+        });
+        let (text, events) = collect(&mut stream).await;
+        assert_eq!(text, reply);
+        assert_eq!(
+            matches!(events.last(), Some(CompletionEvent::Done)),
+            !no_done
+        );
+        assert!(stream.next().await.is_none());
+
+        let summary = stream.into_summary();
+        assert_eq!(summary.text, reply);
+        assert!(summary.stop_sequence.is_none());
+    }
+}
+
+#[tokio::test]
+async fn test_stream_request_headers() {
+    let mock = MockUpstream::start(MockOptions::default()).await;
+    let mut stream = open(test_state(&mock.url, &["--model", "mock-model"]))
+        .await
+        .unwrap();
+    collect(&mut stream).await;
+
+    let headers = mock.state.headers.lock().unwrap().last().cloned().unwrap();
+    assert_eq!(headers["origin"], mock.url.as_str());
+    assert_eq!(
+        headers["openai-sentinel-chat-requirements-token"],
+        "mock-token"
+    );
+    assert!(headers["openai-sentinel-proof-token"]
+        .to_str()
+        .unwrap()
+        .starts_with("gAAAAAB"));
+
+    let body = mock.last_conversation().unwrap();
+    assert_eq!(body["model"], "mock-model");
+    assert_eq!(body["messages"][0]["content"]["parts"][0], "hi");
+}
+
+#[tokio::test]
+async fn test_stream_heartbeats() {
+    let mock = MockUpstream::start(MockOptions {
+        heartbeats: true,
+        ..Default::default()
+    })
+    .await;
+    let mut stream = open(test_state(&mock.url, &[])).await.unwrap();
+    let (text, events) = collect(&mut stream).await;
+
+    assert_eq!(text, MockOptions::default().reply);
+    assert!(events
+        .iter()
+        .any(|event| matches!(event, CompletionEvent::Heartbeat)));
+}
+
+#[tokio::test]
+async fn test_stream_split_and_slow_chunks() {
+    let mock = MockUpstream::start(MockOptions {
+        reply: "Ünïcödé 你好 split across chunks".to_string(),
+        chunk_size: Some(5),
+        chunk_delay: Some(Duration::from_millis(1)),
+        ..Default::default()
+    })
+    .await;
+    let mut stream = open(test_state(&mock.url, &[])).await.unwrap();
+    let (text, _) = collect(&mut stream).await;
+    assert_eq!(text, "Ünïcödé 你好 split across chunks");
+}
+
+#[tokio::test]
+async fn test_stream_whole_body_in_one_chunk() {
+    let mock = MockUpstream::start(MockOptions {
+        heartbeats: true,
+        chunk_size: Some(usize::MAX),
+        ..Default::default()
+    })
+    .await;
+    let mut stream = open(test_state(&mock.url, &[])).await.unwrap();
+    let (text, events) = collect(&mut stream).await;
+    assert_eq!(text, MockOptions::default().reply);
+    assert!(matches!(events.last(), Some(CompletionEvent::Done)));
+}
+
+#[tokio::test]
+async fn test_stream_malformed_frame() {
+    let mock = MockUpstream::start(MockOptions {
+        malformed: true,
+        ..Default::default()
+    })
+    .await;
+    let mut stream = open(test_state(&mock.url, &[])).await.unwrap();
+    let mut text = String::new();
+    let mut errors = 0;
+    while let Some(event) = stream.next().await {
+        match event {
+            Ok(CompletionEvent::Data(data)) => {
+                text.push_str(data.delta_chars.as_deref().unwrap_or_default())
+            }
+            Ok(CompletionEvent::Done) => break,
+            Ok(_) => {}
+            Err(fgpt::Error::Stream(_)) => errors += 1,
+            Err(e) => panic!("unexpected error: {:?}", e),
+        }
+    }
+    // reported, then the stream goes on
+    assert_eq!(errors, 1);
+    assert_eq!(text, MockOptions::default().reply);
+}
+
+#[tokio::test]
+async fn test_stream_error_payload() {
+    let mock = MockUpstream::start(MockOptions {
+        error: Some("Something went wrong".to_string()),
+        ..Default::default()
+    })
+    .await;
+    let mut stream = open(test_state(&mock.url, &[])).await.unwrap();
+    let mut reason = None;
+    while let Some(Ok(event)) = stream.next().await {
+        if let CompletionEvent::Error(e) = event {
+            reason = Some(e);
+            break;
+        }
+    }
+    assert_eq!(reason.as_deref(), Some("Something went wrong"));
+}
+
+#[tokio::test]
+async fn test_stream_retry_on_5xx() {
+    let mock = MockUpstream::start(MockOptions {
+        fail_status: Some((503, 2)),
+        ..Default::default()
+    })
+    .await;
+    let mut stream = open(test_state(&mock.url, &[])).await.unwrap();
+    let (text, _) = collect(&mut stream).await;
+    assert_eq!(text, MockOptions::default().reply);
+    assert_eq!(mock.conversation_calls(), 3);
+    assert_eq!(mock.sentinel_calls(), 3);
+}
+
+#[tokio::test]
+async fn test_stream_rate_limited() {
+    let mock = MockUpstream::start(MockOptions {
+        fail_status: Some((429, usize::MAX)),
+        ..Default::default()
+    })
+    .await;
+    let e = open(test_state(&mock.url, &["--retry-attempts", "2"]))
+        .await
+        .err()
+        .unwrap();
+    assert!(matches!(e, fgpt::Error::RateLimited { .. }), "{:?}", e);
+    assert_eq!(mock.conversation_calls(), 2);
+}
+
+#[tokio::test]
+async fn test_stream_unsupported_region() {
+    let mock = MockUpstream::start(MockOptions {
+        sentinel_status: Some(403),
+        ..Default::default()
+    })
+    .await;
+    let e = open(test_state(&mock.url, &[])).await.err().unwrap();
+    match e {
+        fgpt::Error::Session(e) => {
+            assert!(matches!(*e, fgpt::Error::UnsupportedRegion(_)), "{:?}", e)
+        }
+        e => panic!("unexpected error: {:?}", e),
+    }
+    // not retryable
+    assert_eq!(mock.sentinel_calls(), 1);
+}
@@ -0,0 +1,68 @@
+use crate::tokens::TokenCounter;
+use std::time::Instant;
+
+const SAMPLE: &str = "Here's a short answer:  it's   fine.\n\n```rust\nfn main() {\n    println!(\"hello, world\");\n}\n```\nNumbers 12345 and 3.14, émigré naïve café, 你好 世界 — done! ";
+
+#[test]
+fn test_token_counter_exact() {
+    let tokenizer = gpt_tokenizer::Default::new();
+    let mut counter = TokenCounter::new();
+    let mut text = String::new();
+    for c in SAMPLE.chars() {
+        text.push(c);
+        assert_eq!(
+            counter.update(&tokenizer, &text),
+            tokenizer.encode(&text).len(),
+            "{:?}",
+            text
+        );
+    }
+}
+
+#[test]
+fn test_token_counter_rewritten_text() {
+    let tokenizer = gpt_tokenizer::Default::new();
+    let mut counter = TokenCounter::new();
+    counter.update(&tokenizer, "hello brave new world");
+    assert_eq!(
+        counter.update(&tokenizer, "bye"),
+        tokenizer.encode("bye").len()
+    );
+}
+
+/// cargo test --release bench_token_counter -- --ignored --nocapture
+#[test]
+#[ignore]
+fn bench_token_counter() {
+    let tokenizer = gpt_tokenizer::Default::new();
+    let reply = SAMPLE.repeat(60);
+    let deltas = reply.split_inclusive(' ').collect::<Vec<_>>();
+
+    let start_at = Instant::now();
+    let mut text = String::new();
+    let mut full = 0;
+    for delta in deltas.iter() {
+        text.push_str(delta);
+        full = tokenizer.encode(&text).len();
+    }
+    let full_elapsed = start_at.elapsed();
+
+    let start_at = Instant::now();
+    let mut counter = TokenCounter::new();
+    let mut text = String::new();
+    let mut incremental = 0;
+    for delta in deltas.iter() {
+        text.push_str(delta);
+        incremental = counter.update(&tokenizer, &text);
+    }
+    let incremental_elapsed = start_at.elapsed();
+
+    assert_eq!(full, incremental);
+    println!(
+        "{} tokens, {} deltas: full re-encode {:?}, incremental {:?}",
+        full,
+        deltas.len(),
+        full_elapsed,
+        incremental_elapsed
+    );
+}
@@ -0,0 +1,58 @@
+use crate::tools::{self, FunctionDef, Tool};
+
+fn weather_tool() -> Tool {
+    Tool {
+        r#type: "function".to_string(),
+        function: FunctionDef {
+            name: "get_weather".to_string(),
+            description: None,
+            parameters: None,
+        },
+    }
+}
+
+#[test]
+fn test_strip_fences() {
+    assert_eq!(
+        tools::strip_fences("```json\n{\"a\": 1}\n```"),
+        "{\"a\": 1}"
+    );
+    assert_eq!(tools::strip_fences("```\n{}\n```"), "{}");
+    assert_eq!(tools::strip_fences("  {}  "), "{}");
+    assert_eq!(tools::strip_fences("plain text"), "plain text");
+}
+
+#[test]
+fn test_may_be_tool_call() {
+    assert!(tools::may_be_tool_call(""));
+    assert!(tools::may_be_tool_call("  {\"tool"));
+    assert!(tools::may_be_tool_call("``"));
+    assert!(tools::may_be_tool_call("```json"));
+    assert!(!tools::may_be_tool_call("The weather"));
+}
+
+#[test]
+fn test_parse_tool_calls() {
+    let tools = [weather_tool()];
+    let calls = tools::parse_tool_calls(
+        "```json\n{\"tool_calls\": [{\"name\": \"get_weather\", \"arguments\": {\"city\": \"Paris\"}}]}\n```",
+        &tools,
+    )
+    .unwrap();
+    assert_eq!(calls.len(), 1);
+    assert!(calls[0].id.starts_with("call_"));
+    assert_eq!(calls[0].function.name, "get_weather");
+    assert_eq!(calls[0].function.arguments, r#"{"city":"Paris"}"#);
+
+    // arguments already encoded
+    let calls = tools::parse_tool_calls(
+        r#"{"tool_calls": [{"name": "get_weather", "arguments": "{\"city\":\"Oslo\"}"}]}"#,
+        &tools,
+    )
+    .unwrap();
+    assert_eq!(calls[0].function.arguments, r#"{"city":"Oslo"}"#);
+
+    assert!(tools::parse_tool_calls(r#"{"tool_calls": [{"name": "rm"}]}"#, &tools).is_none());
+    assert!(tools::parse_tool_calls(r#"{"tool_calls": []}"#, &tools).is_none());
+    assert!(tools::parse_tool_calls("It is sunny", &tools).is_none());
+}
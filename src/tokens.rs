@@ -0,0 +1,71 @@
+/// Counts the tokens of a text that only grows, such as the cumulative
+/// `parts` of the upstream, without encoding it from scratch on every delta.
+///
+/// The tokenizer splits the text with
+/// `'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+$|\s+`
+/// before the BPE merges, and no piece can start with a space that follows a
+/// non-space character and is followed by one. The text before such a space
+/// is encoded the same way whatever comes after it, so its tokens are counted
+/// once and only the suffix after the last boundary is encoded again.
+#[derive(Default)]
+pub struct TokenCounter {
+    /// Bytes of the text whose tokens are settled.
+    stable_len: usize,
+    stable_tokens: usize,
+    tail_tokens: usize,
+}
+
+impl TokenCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn count(&self) -> usize {
+        self.stable_tokens + self.tail_tokens
+    }
+
+    /// The last split boundary of `text` after `from`.
+    fn last_boundary(text: &str, from: usize) -> Option<usize> {
+        let bytes = text.as_bytes();
+        let is_space = |c: Option<char>| c.map(char::is_whitespace).unwrap_or(true);
+        (from.max(1)..bytes.len().saturating_sub(1))
+            .rev()
+            .find(|&i| {
+                bytes[i] == b' '
+                    && !is_space(text[..i].chars().next_back())
+                    && !is_space(text[i + 1..].chars().next())
+            })
+    }
+
+    /// Update with the whole text so far and return the total token count.
+    pub fn update(&mut self, tokenizer: &gpt_tokenizer::Default, text: &str) -> usize {
+        if text.len() < self.stable_len || !text.is_char_boundary(self.stable_len) {
+            // the text was rewritten, start over
+            *self = Self::default();
+        }
+        if let Some(boundary) = Self::last_boundary(text, self.stable_len) {
+            if boundary > self.stable_len {
+                self.stable_tokens += tokenizer.encode(&text[self.stable_len..boundary]).len();
+                self.stable_len = boundary;
+            }
+        }
+        self.tail_tokens = tokenizer.encode(&text[self.stable_len..]).len();
+        self.count()
+    }
+}
+
+/// Byte length of the first `max_tokens` tokens of `text`.
+pub fn truncate(tokenizer: &gpt_tokenizer::Default, text: &str, max_tokens: usize) -> usize {
+    let tokens = tokenizer.encode(text);
+    if tokens.len() <= max_tokens {
+        return text.len();
+    }
+    let mut end = tokenizer
+        .decode(&tokens[..max_tokens])
+        .len()
+        .min(text.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    end
+}
@@ -0,0 +1,162 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// A tool the client offers, only functions are supported.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Tool {
+    pub r#type: String,
+    pub function: FunctionDef,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct FunctionDef {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<Value>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ToolChoice {
+    /// `none`, `auto` or `required`
+    Mode(String),
+    Function {
+        function: FunctionName,
+    },
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct FunctionName {
+    pub name: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ToolCall {
+    pub id: String,
+    pub r#type: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct FunctionCall {
+    pub name: String,
+    /// The arguments as a JSON encoded string.
+    pub arguments: String,
+}
+
+/// The system prompt describing the tools, `None` when the model must not
+/// call any.
+pub fn system_prompt(tools: &[Tool], tool_choice: Option<&ToolChoice>) -> Option<String> {
+    if tools.is_empty() {
+        return None;
+    }
+    let rule = match tool_choice {
+        Some(ToolChoice::Mode(mode)) if mode == "none" => return None,
+        Some(ToolChoice::Mode(mode)) if mode == "required" => {
+            "You must call at least one tool.".to_string()
+        }
+        Some(ToolChoice::Function { function }) => {
+            format!("You must call the tool `{}`.", function.name)
+        }
+        _ => "If no tool is needed, answer the user directly.".to_string(),
+    };
+    let functions = tools.iter().map(|tool| &tool.function).collect::<Vec<_>>();
+    Some(format!(
+        "You can call the following tools, described as JSON schemas:\n{}\n\n\
+        To call tools, reply with only a JSON object in this format and nothing else:\n\
+        {{\"tool_calls\": [{{\"name\": \"<tool name>\", \"arguments\": {{<arguments matching the tool parameters>}}}}]}}\n\
+        {} The tool results come back in later messages.",
+        serde_json::to_string_pretty(&functions).unwrap_or_default(),
+        rule
+    ))
+}
+
+/// The calls of an assistant message, written the way the model is asked to
+/// answer, so the history reads consistently.
+pub fn format_tool_calls(calls: &[ToolCall]) -> String {
+    let calls = calls
+        .iter()
+        .map(|call| {
+            let arguments = serde_json::from_str::<Value>(&call.function.arguments)
+                .unwrap_or_else(|_| Value::String(call.function.arguments.clone()));
+            json!({"name": call.function.name, "arguments": arguments})
+        })
+        .collect::<Vec<_>>();
+    json!({ "tool_calls": calls }).to_string()
+}
+
+/// The result of a tool call, sent back as a user message.
+pub fn format_tool_result(tool_call_id: Option<&str>, content: &str) -> String {
+    format!(
+        "The tool call {} returned:\n{}",
+        tool_call_id.unwrap_or("-"),
+        content
+    )
+}
+
+/// The text inside a Markdown code fence, the text itself without one.
+pub fn strip_fences(text: &str) -> &str {
+    let text = text.trim();
+    match text
+        .strip_prefix("```")
+        .and_then(|text| text.strip_suffix("```"))
+    {
+        // skip the info string, such as `json`
+        Some(inner) => inner
+            .split_once('\n')
+            .map(|(_, body)| body)
+            .unwrap_or(inner)
+            .trim(),
+        None => text,
+    }
+}
+
+/// Whether a partial reply may still turn out to be a tool call, such
+/// replies are held back until complete.
+pub fn may_be_tool_call(text: &str) -> bool {
+    let text = text.trim_start();
+    text.starts_with('{') || text.starts_with("```") || "```".starts_with(text)
+}
+
+/// Parse a complete reply into tool calls, `None` if it is a plain answer or
+/// names an unknown tool.
+pub fn parse_tool_calls(text: &str, tools: &[Tool]) -> Option<Vec<ToolCall>> {
+    let value = serde_json::from_str::<Value>(strip_fences(text)).ok()?;
+    let calls = value
+        .get("tool_calls")?
+        .as_array()?
+        .iter()
+        .map(|call| {
+            let name = call.get("name")?.as_str()?;
+            if !tools.iter().any(|tool| tool.function.name == name) {
+                return None;
+            }
+            let arguments = match call.get("arguments") {
+                Some(Value::String(arguments)) => arguments.clone(),
+                Some(arguments) => arguments.to_string(),
+                None => "{}".to_string(),
+            };
+            Some(ToolCall {
+                id: call_id(),
+                r#type: "function".to_string(),
+                function: FunctionCall {
+                    name: name.to_string(),
+                    arguments,
+                },
+            })
+        })
+        .collect::<Option<Vec<_>>>()?;
+    (!calls.is_empty()).then_some(calls)
+}
+
+fn call_id() -> String {
+    let id = rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(24)
+        .map(char::from)
+        .collect::<String>();
+    format!("call_{}", id)
+}